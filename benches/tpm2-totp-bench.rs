@@ -0,0 +1,21 @@
+use popsec::tpm2_totp::Tpm2Totp;
+use std::time::Instant;
+
+const ITERATIONS: u32 = 20;
+
+fn main() {
+    let mut tpm2_totp = Tpm2Totp::new().unwrap();
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        tpm2_totp.invalidate_cache();
+        tpm2_totp.show().unwrap();
+    }
+    println!("uncached show: {:?} per call", start.elapsed() / ITERATIONS);
+
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        tpm2_totp.show().unwrap();
+    }
+    println!("cached show: {:?} per call", start.elapsed() / ITERATIONS);
+}
@@ -0,0 +1,183 @@
+use popsec::tpm2_totp::*;
+use serde::Deserialize;
+use std::{
+    fs,
+    io,
+};
+
+/// Site-wide defaults for the TPM2 TOTP secret, which administrators can set without rebuilding.
+pub const CONFIG_PATH: &str = "/etc/popsec/config.toml";
+
+// Range of NV indices that applications may define, as opposed to those reserved for the
+// platform and TPM vendors
+const NV_INDEX_FIRST: u32 = 0x0100_0000;
+const NV_INDEX_LAST: u32 = 0x01BF_FFFF;
+
+// Highest PCR of the PC client platform
+const PCR_LAST: u32 = 23;
+
+// As written in the file, with every setting optional
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    nv_index: Option<u32>,
+    pcrs: Option<Vec<u32>>,
+    banks: Option<Vec<String>>,
+}
+
+/// Settings applied to every `Tpm2Totp` of the daemon, with the built in defaults for those that
+/// are not configured.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub nv_index: Option<u32>,
+    pub pcrs: u32,
+    pub banks: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            nv_index: None,
+            pcrs: Tpm2Totp::PCRS,
+            banks: Tpm2Totp::BANKS,
+        }
+    }
+}
+
+impl Config {
+    /// Read and validate `CONFIG_PATH`. A missing file gives the defaults.
+    pub fn load() -> Result<Self, String> {
+        let data = match fs::read_to_string(CONFIG_PATH) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(format!("failed to read {}: {}", CONFIG_PATH, err)),
+        };
+        Self::parse(&data).map_err(|err| format!("invalid {}: {}", CONFIG_PATH, err))
+    }
+
+    fn parse(data: &str) -> Result<Self, String> {
+        let file: ConfigFile = toml::from_str(data).map_err(|err| err.to_string())?;
+        let mut config = Self::default();
+
+        if let Some(nv_index) = file.nv_index {
+            if ! (NV_INDEX_FIRST..=NV_INDEX_LAST).contains(&nv_index) {
+                return Err(format!(
+                    "nv_index 0x{:08X} is outside of 0x{:08X} to 0x{:08X}",
+                    nv_index, NV_INDEX_FIRST, NV_INDEX_LAST
+                ));
+            }
+            config.nv_index = Some(nv_index);
+        }
+
+        if let Some(pcrs) = file.pcrs {
+            if pcrs.is_empty() {
+                return Err("pcrs must not be empty".to_string());
+            }
+            config.pcrs = 0;
+            for pcr in pcrs {
+                if pcr > PCR_LAST {
+                    return Err(format!("PCR {} is above {}", pcr, PCR_LAST));
+                }
+                config.pcrs |= 1 << pcr;
+            }
+        }
+
+        if let Some(banks) = file.banks {
+            if banks.is_empty() {
+                return Err("banks must not be empty".to_string());
+            }
+            config.banks = 0;
+            for bank in banks {
+                config.banks |= match bank.to_ascii_lowercase().as_str() {
+                    "sha1" => Tpm2Totp::BANK_SHA1,
+                    "sha256" => Tpm2Totp::BANK_SHA256,
+                    "sha384" => Tpm2Totp::BANK_SHA384,
+                    _ => return Err(format!("unsupported bank '{}', expected sha1, sha256, or sha384", bank)),
+                };
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The effective settings, for the log.
+    pub fn summary(&self) -> String {
+        let pcrs: Vec<String> = (0..32)
+            .filter(|pcr| self.pcrs & (1 << pcr) != 0)
+            .map(|pcr: u32| pcr.to_string())
+            .collect();
+        let banks: Vec<&str> = [
+            (Tpm2Totp::BANK_SHA1, "sha1"),
+            (Tpm2Totp::BANK_SHA256, "sha256"),
+            (Tpm2Totp::BANK_SHA384, "sha384"),
+        ].iter()
+            .filter(|(bank, _)| self.banks & bank != 0)
+            .map(|(_, name)| *name)
+            .collect();
+        format!(
+            "NV index {}, PCRs {}, banks {}",
+            self.nv_index.map_or("default".to_string(), |nv_index| format!("0x{:08X}", nv_index)),
+            pcrs.join(", "),
+            banks.join(", ")
+        )
+    }
+
+    /// Open the TPM with these settings. In FIPS mode, the banks are replaced by those it allows.
+    pub fn tpm2_totp(&self) -> Result<Tpm2Totp, TotpError> {
+        let mut tpm2_totp = Tpm2Totp::new()?
+            .with_pcrs(self.pcrs)
+            .with_banks(self.banks);
+        if let Some(nv_index) = self.nv_index {
+            tpm2_totp = tpm2_totp.with_nv_index(nv_index);
+        }
+        Ok(if fips_enabled() {
+            tpm2_totp.fips_mode()
+        } else {
+            tpm2_totp
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_empty_gives_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.nv_index, None);
+        assert_eq!(config.pcrs, Tpm2Totp::PCRS);
+        assert_eq!(config.banks, Tpm2Totp::BANKS);
+    }
+
+    #[test]
+    fn parse_settings() {
+        let config = Config::parse("nv_index = 0x01800001\npcrs = [0, 7]\nbanks = [\"SHA256\"]\n").unwrap();
+        assert_eq!(config.nv_index, Some(0x0180_0001));
+        assert_eq!(config.pcrs, (1 << 0) | (1 << 7));
+        assert_eq!(config.banks, Tpm2Totp::BANK_SHA256);
+    }
+
+    #[test]
+    fn parse_unknown_key() {
+        assert!(Config::parse("pcr = [0]\n").is_err());
+    }
+
+    #[test]
+    fn parse_nv_index_out_of_range() {
+        assert!(Config::parse("nv_index = 0x00FFFFFF\n").is_err());
+        assert!(Config::parse("nv_index = 0x01C00002\n").is_err());
+    }
+
+    #[test]
+    fn parse_pcr_out_of_range() {
+        assert!(Config::parse("pcrs = [24]\n").is_err());
+        assert!(Config::parse("pcrs = []\n").is_err());
+    }
+
+    #[test]
+    fn parse_unsupported_bank() {
+        assert!(Config::parse("banks = [\"sha512\"]\n").is_err());
+        assert!(Config::parse("banks = []\n").is_err());
+    }
+}
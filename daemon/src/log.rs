@@ -0,0 +1,72 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+// Set by `--log-format json`, before anything is logged
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Log a message on stderr, formatted like `format!`.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        crate::log::message(&format!($($arg)*))
+    };
+}
+
+/// Format of the lines written to stderr.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Lines prefixed with `popsec-daemon:`, as read by people in the journal.
+    Text,
+    /// One JSON object per line, for log aggregation. Each method call is logged as well.
+    Json,
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            },
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+pub fn message(message: &str) {
+    if JSON.load(Ordering::Relaxed) {
+        eprintln!("{{\"message\":{}}}", json_string(message));
+    } else {
+        eprintln!("popsec-daemon: {}", message);
+    }
+}
+
+/// Log a method call and its outcome, where `error` is the name of the DBus error it failed with.
+/// Only JSON logs have a line per method call, as text logs are read by people.
+pub fn method_call(method: &str, caller: &str, error: Option<&str>, duration: Duration) {
+    if ! JSON.load(Ordering::Relaxed) {
+        return;
+    }
+    eprintln!(
+        "{{\"method\":{},\"caller\":{},\"outcome\":\"{}\",\"error\":{},\"duration_ms\":{}}}",
+        json_string(method),
+        json_string(caller),
+        if error.is_some() { "error" } else { "ok" },
+        error.map_or("null".to_string(), json_string),
+        duration.as_millis()
+    );
+}
@@ -1,51 +1,713 @@
-use dbus::blocking::Connection;
+use dbus::{
+    arg::{Append, Arg, ArgType, IterAppend},
+    blocking::{
+        stdintf::org_freedesktop_dbus::RequestNameReply,
+        Connection,
+    },
+    channel::Sender,
+    message::MatchRule,
+    Message,
+    Signature,
+};
 use dbus_crossroads::{Crossroads, Context, MethodErr};
 use popsec::{
     dbus::*,
+    event_log,
+    otpauth,
+    secure_boot,
+    status::Health,
     tpm2_totp::*,
 };
-use std::{process};
+use std::{
+    collections::HashMap,
+    env,
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+    cell::RefCell,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
+use config::Config;
+use log::LogFormat;
+
+#[macro_use]
+mod log;
+mod config;
+mod polkit;
+mod socket;
 
 fn err_str<E: ::std::fmt::Display>(err: E) -> String {
     format!("{}", err)
 }
 
+#[derive(Default)]
+struct Args {
+    socket: Option<PathBuf>,
+    // Serve on the session bus instead of the system bus, so the daemon can be tested without root
+    session: bool,
+    // For provisioning without a display, the otpauth URL of a secret set up by `init` is written
+    // to this root-only file, which must not exist yet. Delete it once it has been fetched, as
+    // anyone reading it can generate codes.
+    provision_secret: Option<PathBuf>,
+    // Exit after this long without method calls, to be started again by DBus activation
+    idle_timeout: Option<Duration>,
+    log_format: Option<LogFormat>,
+}
+
+fn log_format(format: &str) -> Result<LogFormat, String> {
+    match format {
+        "text" => Ok(LogFormat::Text),
+        "json" => Ok(LogFormat::Json),
+        _ => Err(format!("invalid --log-format '{}', expected text or json", format)),
+    }
+}
+
+fn args() -> Result<Args, String> {
+    let mut args = Args::default();
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--socket" => {
+                let path = iter.next().ok_or("--socket requires a path")?;
+                args.socket = Some(PathBuf::from(path));
+            },
+            "--session" => {
+                args.session = true;
+            },
+            "--idle-timeout" => {
+                let secs = iter.next().ok_or("--idle-timeout requires a number of seconds")?;
+                let secs = secs.parse::<u64>().map_err(|err| format!("invalid --idle-timeout: {}", err))?;
+                args.idle_timeout = Some(Duration::from_secs(secs));
+            },
+            "--provision-secret" => {
+                let path = iter.next().ok_or("--provision-secret requires a path")?;
+                args.provision_secret = Some(PathBuf::from(path));
+            },
+            "--log-format" => {
+                let format = iter.next().ok_or("--log-format requires text or json")?;
+                args.log_format = Some(log_format(&format)?);
+            },
+            _ if arg.starts_with("--log-format=") => {
+                args.log_format = Some(log_format(&arg["--log-format=".len()..])?);
+            },
+            _ => return Err(format!("unknown argument '{}'", arg)),
+        }
+    }
+    Ok(args)
+}
+
+// Directory for state kept across restarts of the daemon
+const STATE_DIR: &str = "/var/lib/popsec";
+
+// Summary of the event log when the secret was last sealed, to tell what changed since
+const EVENT_LOG_SUMMARY: &str = "/var/lib/popsec/event-log";
+
+// Key blob written during a reseal, to restore the secret if the reseal is interrupted
+const RESEAL_JOURNAL: &str = "/var/lib/popsec/reseal.journal";
+
+// Account of the otpauth URL at enrollment, so the URL keeps matching the authenticator entry after
+// the hostname changes
+const ACCOUNT: &str = "/var/lib/popsec/account";
+
+// Digest of the Secure Boot keys when the secret was set up, to tell whether they changed since
+const SECURE_BOOT_BASELINE: &str = "/var/lib/popsec/secure-boot-baseline";
+
+fn save_secure_boot_baseline() {
+    let result = secure_boot::keys_digest()
+        .and_then(|digest| fs::write(SECURE_BOOT_BASELINE, format!("{}\n", digest)));
+    if let Err(err) = result {
+        log!("failed to save Secure Boot baseline: {}", err);
+    }
+}
+
+fn save_account(account: &str) {
+    if let Err(err) = fs::write(ACCOUNT, format!("{}\n", account)) {
+        log!("failed to save otpauth account: {}", err);
+    }
+}
+
+// Secrets enrolled before the account was saved use the current hostname
+fn enrolled_account() -> String {
+    match fs::read_to_string(ACCOUNT) {
+        Ok(account) if ! account.trim().is_empty() => account.trim().to_string(),
+        _ => otpauth::account(),
+    }
+}
+
+// The secret as a byte array in the reply of `METHOD_TPM2_TOTP_INIT`, appended without copying it
+// to memory that is not wiped
+struct SecretReply(TotpSecret);
+
+impl Arg for SecretReply {
+    const ARG_TYPE: ArgType = ArgType::Array;
+    fn signature() -> Signature<'static> {
+        <Vec<u8> as Arg>::signature()
+    }
+}
+
+impl Append for SecretReply {
+    fn append_by_ref(&self, i: &mut IterAppend) {
+        self.0.as_bytes().append_by_ref(i);
+    }
+}
+
+fn write_provision_secret(path: &Path, secret: &TotpSecret) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    writeln!(file, "{}", secret.otpauth_url(otpauth::ISSUER, &enrolled_account()))?;
+    file.sync_all()
+}
+
+fn save_event_log_summary(pcrs: u32) {
+    let result = event_log::read().and_then(|events| {
+        let mut summary = event_log::summary(&events, pcrs).join("\n");
+        summary.push('\n');
+        fs::write(EVENT_LOG_SUMMARY, summary)
+    });
+    if let Err(err) = result {
+        log!("failed to save event log summary: {}", err);
+    }
+}
+
+// Shortest time between show calls of a caller that are passed on to the TPM
+const SHOW_MIN_INTERVAL: Duration = Duration::from_secs(2);
+
+fn current_window() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs() / TOTP_PERIOD)
+}
+
+// Forward the phases of an init or reseal as signals, until the sender is dropped
+fn forward_progress(session: bool) -> mpsc::Sender<Progress> {
+    let (sender, receiver) = mpsc::channel::<Progress>();
+    thread::spawn(move || {
+        // Signals are sent on a connection of their own, as the main one is busy with the call
+        let c = match if session { Connection::new_session() } else { Connection::new_system() } {
+            Ok(ok) => ok,
+            Err(err) => {
+                log!("failed to connect to send progress: {}", err);
+                return;
+            }
+        };
+        for progress in receiver {
+            let signal = Message::new_signal(DBUS_PATH, DBUS_IFACE, SIGNAL_TPM2_TOTP_PROGRESS)
+                .map(|m| m.append3(progress.phase.as_u32(), progress.step, progress.steps));
+            match signal {
+                Ok(signal) => {
+                    let _ = c.send(signal);
+                    c.channel().flush();
+                },
+                Err(err) => log!("failed to create progress signal: {}", err),
+            }
+        }
+    });
+    sender
+}
+
+// Note what the library did on its own to get a result, which callers do not see
+fn log_notices(tpm2_totp: &mut Tpm2Totp) {
+    if let Some(nv_index) = tpm2_totp.take_discovered_index() {
+        log!(
+            "no secret at NV index 0x{:08X}, using secret found at 0x{:08X}",
+            tpm2_totp.nv_index(),
+            nv_index
+        );
+    }
+    if let Some(err) = tpm2_totp.take_fallback_error() {
+        log!("fell back to tpm2-totp command after error: {}", err);
+    }
+}
+
+// Slow NV writes can stall init and reseal for seconds, so note how long they took
+fn log_timing(operation: &str, duration: Option<Duration>) {
+    if let Some(duration) = duration {
+        log!("{} took {} ms", operation, duration.as_millis());
+    }
+}
+
+fn event_log_changes() -> Vec<String> {
+    // Without a summary from sealing, or without an event log, there is nothing to compare
+    let previous = match fs::read_to_string(EVENT_LOG_SUMMARY) {
+        Ok(ok) => ok.lines().map(String::from).collect::<Vec<_>>(),
+        Err(_) => return Vec::new(),
+    };
+    let events = match event_log::read() {
+        Ok(ok) => ok,
+        Err(_) => return Vec::new(),
+    };
+    let pcrs = previous.iter()
+        .filter_map(|line| line.strip_prefix("PCR ")?.split(':').next()?.parse::<u32>().ok())
+        .filter(|pcr| *pcr < 32)
+        .fold(0, |mask, pcr| mask | (1 << pcr));
+    event_log::changes(&previous, &event_log::summary(&events, pcrs))
+}
+
+// The polkit action that callers of a method must be authorized for, if any
+fn polkit_action(method: &str) -> Option<&'static str> {
+    match method {
+        METHOD_SECURE_BOOT_ENROLL_DEFAULT_KEYS => Some("com.system76.PopSec.secure-boot-enroll"),
+        METHOD_SET_MAINTENANCE => Some("com.system76.PopSec.maintenance"),
+        METHOD_TPM2_TOTP_OTPAUTH => Some("com.system76.PopSec.tpm2-totp-otpauth"),
+        _ => None,
+    }
+}
+
+// Passes on the reply to a method call, noting the name of the error if it is one
+struct ReplySender<'a> {
+    conn: &'a Connection,
+    error: RefCell<Option<String>>,
+}
+
+impl<'a> Sender for ReplySender<'a> {
+    fn send(&self, mut msg: Message) -> Result<u32, ()> {
+        if let Err(err) = msg.as_result() {
+            *self.error.borrow_mut() = Some(err.name().unwrap_or("").to_string());
+        }
+        self.conn.send(msg)
+    }
+}
+
+// Handle a method call, or reply with the error that denied it, and log its outcome
+fn handle_method_call(
+    cr: &Mutex<Crossroads>,
+    msg: Message,
+    conn: &Connection,
+    start: Instant,
+    authorized: Result<(), MethodErr>,
+) {
+    let method = msg.member().map_or(String::new(), |x| x.to_string());
+    let caller = msg.sender().map_or(String::new(), |x| x.to_string());
+    let sender = ReplySender {
+        conn,
+        error: RefCell::new(None),
+    };
+    match authorized {
+        Ok(()) => cr.lock().unwrap_or_else(|err| err.into_inner()).handle_message(msg, &sender).unwrap(),
+        Err(err) => {
+            let _ = sender.send(err.to_message(&msg));
+        }
+    }
+    log::method_call(&method, &caller, sender.error.borrow().as_deref(), start.elapsed());
+}
+
 fn daemon() -> Result<(), String> {
-    if unsafe { libc::geteuid() } != 0 {
+    let args = args()?;
+    log::set_format(args.log_format.unwrap_or(LogFormat::Text));
+
+    if ! args.session && unsafe { libc::geteuid() } != 0 {
         return Err("must be run as root".into());
     }
 
-    struct State;
+    let config = Config::load()?;
+    log!("TPM2 TOTP settings: {}", config.summary());
 
-    let state = State;
+    if fips_enabled() {
+        log!("FIPS mode is enabled, not using the SHA1 PCR bank");
+    }
 
-    let c = Connection::new_system().map_err(err_str)?;
+    if let Err(err) = fs::create_dir_all(STATE_DIR) {
+        log!("failed to create {}: {}", STATE_DIR, err);
+    }
 
-    c.request_name(DBUS_DEST, false, true, false).map_err(err_str)?;
+    // Finish a reseal interrupted by a crash or power loss, before anything reads the secret
+    match Tpm2Totp::new().and_then(|tpm2_totp| tpm2_totp.with_journal(RESEAL_JOURNAL).complete_journal()) {
+        Ok(true) => log!("restored secret from interrupted reseal"),
+        Ok(false) => (),
+        Err(err) => log!("failed to complete interrupted reseal: {}", err),
+    }
+
+    // Set by system updaters while flashing firmware, to not prompt for resealing meanwhile
+    let maintenance = Arc::new(AtomicBool::new(false));
+
+    // Socket clients expect the daemon to keep running
+    let idle_timeout = if args.socket.is_some() { None } else { args.idle_timeout };
+
+    // One TPM2 TOTP instance is shared by all method calls and socket clients, so that the TCTI
+    // context is reused and access to the TPM is serialized. It is opened on first use.
+    let tpm2_totp = Arc::new(Mutex::new(None));
+
+    if let Some(path) = args.socket {
+        socket::serve(path, config.clone(), tpm2_totp.clone(), maintenance.clone())?;
+    }
+
+    struct State {
+        config: Config,
+        tpm2_totp: Arc<Mutex<Option<Tpm2Totp>>>,
+        provision_secret: Option<PathBuf>,
+        maintenance: Arc<AtomicBool>,
+        session: bool,
+        // Time of the last show call of each caller, by unique bus name
+        show_calls: HashMap<String, Instant>,
+        // Window and outcome of the last show call, forgotten whenever the secret or the PCRs may
+        // have changed
+        last_code: Arc<Mutex<Option<(u64, Result<TotpCode, TotpError>)>>>,
+        // Details of the error that last failed a method call using the TPM
+        last_error: Mutex<Option<(String, String, u32)>>,
+    }
+
+    impl State {
+        fn reset_last_code(&self) {
+            *self.last_code.lock().unwrap_or_else(|err| err.into_inner()) = None;
+        }
+
+        fn tpm2_totp<T, F>(&self, f: F) -> Result<T, MethodErr>
+            where F: FnOnce(&mut Tpm2Totp) -> Result<T, TotpError>
+        {
+            self.try_tpm2_totp(f).map_err(|err| self.method_err(err))
+        }
+
+        // Note the details of an error using the TPM for `METHOD_LAST_ERROR`
+        fn method_err(&self, err: TotpError) -> MethodErr {
+            *self.last_error.lock().unwrap_or_else(|err| err.into_inner()) = Some(error_details(&err));
+            MethodErr::from(err)
+        }
+
+        // Callers asking again too soon get the outcome of the last call in this window without
+        // touching the TPM, whether it succeeded or not, so one misbehaving client cannot keep it
+        // busy
+        fn show(&mut self, sender: Option<&str>) -> Result<TotpCode, TotpError> {
+            if self.maintenance.load(Ordering::Relaxed) {
+                return Err(TotpError::Maintenance);
+            }
+
+            let now = Instant::now();
+            let window = current_window();
+            self.show_calls.retain(|_, last| now.duration_since(*last) < SHOW_MIN_INTERVAL);
+            if let Some(sender) = sender {
+                let limited = self.show_calls.insert(sender.to_string(), now).is_some();
+                let last_code = self.last_code.lock().unwrap_or_else(|err| err.into_inner());
+                match &*last_code {
+                    Some((last_window, result)) if limited && *last_window == window => {
+                        return result.clone();
+                    },
+                    _ => (),
+                }
+            }
+
+            let result = self.try_tpm2_totp(|tpm2_totp| tpm2_totp.show());
+            *self.last_code.lock().unwrap_or_else(|err| err.into_inner()) = Some((window, result.clone()));
+            result
+        }
+
+        fn try_tpm2_totp<T, F>(&self, f: F) -> Result<T, TotpError>
+            where F: FnOnce(&mut Tpm2Totp) -> Result<T, TotpError>
+        {
+            let mut guard = self.tpm2_totp.lock().unwrap_or_else(|err| err.into_inner());
+            if guard.is_none() {
+                *guard = Some(self.config.tpm2_totp()?.with_journal(RESEAL_JOURNAL));
+            }
+            let tpm2_totp = guard.as_mut().unwrap();
+            let result = f(tpm2_totp);
+            log_notices(tpm2_totp);
+            // Open a new connection to the TPM, so that calls after a driver reset succeed
+            if let Err(err) = &result {
+                if err.is_transport() {
+                    match tpm2_totp.reconnect() {
+                        Ok(()) => log!("reconnected to the TPM after error: {}", err),
+                        Err(reconnect_err) => log!("failed to reconnect to the TPM: {}", reconnect_err),
+                    }
+                }
+            }
+            result
+        }
+    }
+
+    let state = State {
+        config,
+        tpm2_totp,
+        provision_secret: args.provision_secret,
+        maintenance: maintenance.clone(),
+        session: args.session,
+        show_calls: HashMap::new(),
+        last_code: Arc::new(Mutex::new(None)),
+        last_error: Mutex::new(None),
+    };
+
+    let c = if args.session {
+        Connection::new_session()
+    } else {
+        Connection::new_system()
+    }.map_err(err_str)?;
+
+    let reply = c.request_name(DBUS_DEST, false, true, false).map_err(err_str)?;
+    if reply != RequestNameReply::PrimaryOwner {
+        return Err(format!("failed to become primary owner of {}: {:?}", DBUS_DEST, reply));
+    }
+
+    // If the name is ever taken from us, no client can reach this process anymore, so exit and
+    // let systemd restart the daemon
+    c.add_match(
+        MatchRule::new_signal("org.freedesktop.DBus", "NameLost")
+            .with_sender("org.freedesktop.DBus"),
+        |(name,): (String,), _, _| {
+            if name == DBUS_DEST {
+                log!("lost dbus name {}, exiting", name);
+                process::exit(1);
+            }
+            true
+        }
+    ).map_err(err_str)?;
+
+    // The PCRs and the stored blob may have changed while suspended, so reload the blob on resume
+    {
+        let tpm2_totp = state.tpm2_totp.clone();
+        let last_code = state.last_code.clone();
+        c.add_match(
+            MatchRule::new_signal("org.freedesktop.login1.Manager", "PrepareForSleep")
+                .with_sender("org.freedesktop.login1"),
+            move |(start,): (bool,), _, _| {
+                if ! start {
+                    let mut guard = tpm2_totp.lock().unwrap_or_else(|err| err.into_inner());
+                    if let Some(tpm2_totp) = guard.as_mut() {
+                        tpm2_totp.invalidate_cache();
+                    }
+                    *last_code.lock().unwrap_or_else(|err| err.into_inner()) = None;
+                }
+                true
+            }
+        ).map_err(err_str)?;
+    }
 
     let mut cr = Crossroads::new();
 
     let iface_token = cr.register(DBUS_IFACE, |b| {
+        b.method(
+            METHOD_DIAGNOSTICS,
+            (),
+            ("tpm", "secret", "clock", "lockout", "timings"),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.diagnostics())
+                    .map(|v| (
+                        (v.manufacturer, v.firmware_version),
+                        (v.enrolled, v.pcrs, v.banks),
+                        (v.clock.time, v.clock.clock, v.clock.reset_count, v.clock.restart_count, v.clock.safe),
+                        (v.lockout_counter, v.max_auth_fail, v.lockout_interval, v.lockout_recovery, v.in_lockout),
+                        (
+                            duration_millis(v.timings.init),
+                            duration_millis(v.timings.reseal),
+                            duration_millis(v.timings.show),
+                        ),
+                    ))
+            }
+        );
+        b.method(
+            METHOD_HEALTH,
+            (),
+            ("health",),
+            |ctx: &mut Context, state: &mut State, _inputs: ()| {
+                let health = if tpm_available() {
+                    let sender = ctx.message().sender().map(|x| x.to_string());
+                    Health::new(&state.show(sender.as_deref()))
+                } else {
+                    Health::TpmUnavailable
+                };
+                Ok((health.as_str().to_string(),))
+            }
+        );
+        b.method(
+            METHOD_LAST_ERROR,
+            (),
+            ("name", "message", "rc"),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                // An empty name when no call has failed yet
+                let last_error = state.last_error.lock().unwrap_or_else(|err| err.into_inner());
+                Ok(last_error.clone().unwrap_or_default())
+            }
+        );
+        b.method(
+            METHOD_MEASURED_BOOT_ACTIVE,
+            (),
+            ("active",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.measured_boot_active())
+                    .map(|v| (v,))
+            }
+        );
+        b.method(
+            METHOD_NV_BUDGET,
+            (),
+            ("available_bytes", "max_handles"),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.nv_budget())
+                    .map(|v| (v.available_bytes, v.max_handles))
+            }
+        );
+        b.method(
+            METHOD_PING,
+            (),
+            ("version", "protocol"),
+            |_ctx: &mut Context, _state: &mut State, _inputs: ()| {
+                Ok((env!("CARGO_PKG_VERSION").to_string(), PROTOCOL_VERSION))
+            }
+        );
+        b.method(
+            METHOD_SECURE_BOOT_ENROLL_DEFAULT_KEYS,
+            (),
+            (),
+            |_ctx: &mut Context, _state: &mut State, _inputs: ()| {
+                secure_boot::enroll_default_keys()
+                    .map_err(|err| MethodErr::failed(&err))?;
+                // The keys were changed on purpose, so they are the new baseline
+                if Path::new(SECURE_BOOT_BASELINE).exists() {
+                    save_secure_boot_baseline();
+                }
+                Ok(())
+            }
+        );
+        b.method(
+            METHOD_SECURE_BOOT_KEYS_CHANGED,
+            (),
+            ("changed",),
+            |_ctx: &mut Context, _state: &mut State, _inputs: ()| {
+                let baseline = match fs::read_to_string(SECURE_BOOT_BASELINE) {
+                    Ok(ok) => ok,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((false,)),
+                    Err(err) => return Err(MethodErr::failed(&err)),
+                };
+                secure_boot::keys_digest()
+                    .map(|digest| (digest != baseline.trim(),))
+                    .map_err(|err| MethodErr::failed(&err))
+            }
+        );
+        b.method(
+            METHOD_SECURITY_STATUS,
+            (),
+            ("secure_boot", "setup_mode", "tpm_present", "enrolled"),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                let secure_boot = secure_boot::read_state().map_err(|err| MethodErr::failed(&err))?;
+                let tpm_present = tpm_available();
+                let enrolled = tpm_present && state.tpm2_totp(|tpm2_totp| tpm2_totp.enrolled())?;
+                Ok((secure_boot.secure_boot, secure_boot.setup_mode, tpm_present, enrolled))
+            }
+        );
+        b.method(
+            METHOD_SET_MAINTENANCE,
+            ("enabled",),
+            (),
+            |_ctx: &mut Context, state: &mut State, (enabled,): (bool,)| {
+                if enabled {
+                    state.reset_last_code();
+                }
+                state.maintenance.store(enabled, Ordering::Relaxed);
+                Ok(())
+            }
+        );
+        b.method(
+            METHOD_TPM2_TOTP_ATTEST,
+            (),
+            ("quote",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.attest())
+                    .map(|v| (v,))
+            }
+        );
+        b.method(
+            METHOD_TPM2_TOTP_CHANGES,
+            (),
+            ("changes",),
+            |_ctx: &mut Context, _state: &mut State, _inputs: ()| {
+                Ok((event_log_changes(),))
+            }
+        );
+        b.method(
+            METHOD_TPM2_TOTP_HAS_RECOVERY_PASSWORD,
+            (),
+            ("has_recovery_password",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.has_recovery_password())
+                    .map(|v| (v,))
+            }
+        );
         b.method(
             METHOD_TPM2_TOTP_INIT,
-            ("password",),
+            ("password", "pcrs"),
             ("secret",),
-            |_ctx: &mut Context, _state: &mut State, (password,): (String,)| {
-                let mut tpm2_totp = Tpm2Totp::new().map_err(MethodErr::from)?;
-                tpm2_totp.init(&TotpPass(password))
-                    .map(|v| (v.0,))
-                    .map_err(MethodErr::from)
+            |_ctx: &mut Context, state: &mut State, (password, pcrs): (String, u32)| {
+                // No PCRs selects those of the configuration
+                let pcrs = if pcrs == 0 { state.config.pcrs } else { pcrs };
+                let progress = forward_progress(state.session);
+                state.reset_last_code();
+                let secret = state.tpm2_totp(|tpm2_totp| {
+                    tpm2_totp.set_pcrs(pcrs);
+                    if let Some(advisory) = tpm2_totp.pcr_advisory() {
+                        log!("{}", advisory);
+                    }
+                    tpm2_totp.set_progress(Some(progress));
+                    let result = tpm2_totp.init(&TotpPass(password));
+                    tpm2_totp.set_progress(None);
+                    log_timing("init", tpm2_totp.timings().init);
+                    result
+                })?;
+                save_event_log_summary(pcrs);
+                save_account(&otpauth::account());
+                save_secure_boot_baseline();
+                if let Some(path) = &state.provision_secret {
+                    if let Err(err) = write_provision_secret(path, &secret) {
+                        log!("failed to write secret to {}: {}", path.display(), err);
+                    }
+                }
+                Ok((SecretReply(secret),))
             }
         );
         b.method(
-            METHOD_TPM2_TOTP_RESEAL,
+            METHOD_TPM2_TOTP_NV_LOCKED,
+            (),
+            ("nv_locked",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.nv_locked())
+                    .map(|v| (v,))
+            }
+        );
+        b.method(
+            METHOD_TPM2_TOTP_OTPAUTH,
             ("password",),
+            ("url",),
+            |_ctx: &mut Context, state: &mut State, (password,): (String,)| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.recover(&TotpPass(password)))
+                    .map(|v| (v.otpauth_url(otpauth::ISSUER, &enrolled_account()),))
+            }
+        );
+        b.method(
+            METHOD_TPM2_TOTP_PCR_STATE_MATCHES,
             (),
-            |_ctx: &mut Context, _state: &mut State, (password,): (String,)| {
-                let mut tpm2_totp = Tpm2Totp::new().map_err(MethodErr::from)?;
-                tpm2_totp.reseal(&TotpPass(password))
-                    .map_err(MethodErr::from)?;
+            ("matches",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.pcr_state_matches())
+                    .map(|v| (v,))
+            }
+        );
+        b.method(
+            METHOD_TPM2_TOTP_RESEAL,
+            ("password", "pcrs"),
+            (),
+            |_ctx: &mut Context, state: &mut State, (password, pcrs): (String, u32)| {
+                let pcrs = if pcrs == 0 { state.config.pcrs } else { pcrs };
+                let progress = forward_progress(state.session);
+                state.reset_last_code();
+                state.tpm2_totp(|tpm2_totp| {
+                    tpm2_totp.set_pcrs(pcrs);
+                    let plan = tpm2_totp.prepare_reseal()?;
+                    tpm2_totp.set_progress(Some(progress));
+                    let result = tpm2_totp.commit_reseal(plan, &TotpPass(password));
+                    tpm2_totp.set_progress(None);
+                    log_timing("reseal", tpm2_totp.timings().reseal);
+                    result
+                })?;
+                save_event_log_summary(pcrs);
                 Ok(())
             }
         );
@@ -53,25 +715,148 @@ fn daemon() -> Result<(), String> {
             METHOD_TPM2_TOTP_SHOW,
             (),
             ("code",),
-            |_ctx: &mut Context, _state: &mut State, _inputs: ()| {
-                let mut tpm2_totp = Tpm2Totp::new().map_err(MethodErr::from)?;
-                tpm2_totp.show()
-                    .map(|v| (v.0,))
-                    .map_err(MethodErr::from)
+            |ctx: &mut Context, state: &mut State, _inputs: ()| {
+                let sender = ctx.message().sender().map(|x| x.to_string());
+                match state.show(sender.as_deref()) {
+                    Ok(code) => Ok((code.0,)),
+                    Err(err @ TotpError::Maintenance) => Err(MethodErr::from(err)),
+                    Err(err) => Err(state.method_err(err)),
+                }
+            }
+        );
+        b.method(
+            METHOD_TPM2_TOTP_SHOW_ALL,
+            (),
+            ("codes",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                if state.maintenance.load(Ordering::Relaxed) {
+                    return Err(MethodErr::from(TotpError::Maintenance));
+                }
+
+                let slots = state.tpm2_totp(|tpm2_totp| tpm2_totp.show_all())?;
+                Ok((slots.into_iter().map(|slot| {
+                    let (code, (error_name, error_message)) = match slot.code {
+                        Ok(code) => (code.0, (String::new(), String::new())),
+                        Err(err) => (0, slot_error_name(err)),
+                    };
+                    (slot.nv_index, slot.pcrs, code, error_name, error_message)
+                }).collect::<Vec<_>>(),))
+            }
+        );
+        b.method(
+            METHOD_TPM_ACTIVE_BANKS,
+            (),
+            ("banks",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.active_banks())
+                    .map(|v| (v.iter().map(HashAlg::id).collect::<Vec<u16>>(),))
+            }
+        );
+        b.method(
+            METHOD_TPM_CLOCK,
+            (),
+            ("time", "clock", "reset_count", "restart_count", "safe"),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.clock())
+                    .map(|v| (v.time, v.clock, v.reset_count, v.restart_count, v.safe))
+            }
+        );
+        b.method(
+            METHOD_TPM_EK_VERIFIED,
+            (),
+            ("verified",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                let cert = match state.try_tpm2_totp(|tpm2_totp| tpm2_totp.ek_certificate()) {
+                    Ok(ok) => ok,
+                    Err(err @ TotpError::TpmBusy) => return Err(MethodErr::from(err)),
+                    // Without a certificate, the TPM cannot prove where it comes from
+                    Err(err) => {
+                        log!("failed to read EK certificate: {}", err);
+                        return Ok((false,));
+                    }
+                };
+                verify_ek_certificate(&cert).map(|v| (v,)).map_err(MethodErr::from)
+            }
+        );
+        b.method(
+            METHOD_TPM_TYPE,
+            (),
+            ("type",),
+            |_ctx: &mut Context, state: &mut State, _inputs: ()| {
+                state.tpm2_totp(|tpm2_totp| tpm2_totp.tpm_type())
+                    .map(|v| (v.as_str().to_string(),))
             }
         );
     });
 
     cr.insert(DBUS_PATH, &[iface_token], state);
+    let cr = Arc::new(Mutex::new(cr));
+
+    // On the session bus, every caller is the user running the daemon, and polkit cannot
+    // identify them
+    let authority = if args.session {
+        None
+    } else {
+        Some(polkit::Authority::new().map_err(err_str)?)
+    };
+
+    // Method calls that polkit has answered for, to be handled by the main loop
+    let (authorized_sender, authorized_receiver) = mpsc::channel::<(Message, Instant, Result<(), MethodErr>)>();
+    let pending_checks = Arc::new(AtomicUsize::new(0));
 
-    cr.serve(&c).map_err(err_str)
+    // Like `Crossroads::serve`, but noting the time of each method call for the idle timeout, and
+    // logging its outcome
+    let last_call = Arc::new(Mutex::new(Instant::now()));
+    {
+        let cr = cr.clone();
+        let last_call = last_call.clone();
+        let pending_checks = pending_checks.clone();
+        c.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
+            let start = Instant::now();
+            *last_call.lock().unwrap_or_else(|err| err.into_inner()) = start;
+            let action_id = msg.member().and_then(|x| polkit_action(&x));
+            match (&authority, action_id) {
+                // The user may take minutes to authenticate, so the check must not block the loop
+                (Some(authority), Some(action_id)) => {
+                    let sender = authorized_sender.clone();
+                    pending_checks.fetch_add(1, Ordering::Relaxed);
+                    authority.check_async(msg.sender().map(|x| x.to_string()), action_id, move |result| {
+                        let _ = sender.send((msg, start, result));
+                    });
+                },
+                _ => handle_method_call(&cr, msg, conn, start, Ok(())),
+            }
+            true
+        }));
+    }
+    loop {
+        // Poll more often while waiting for polkit, to reply soon after the user authenticated
+        let timeout = if pending_checks.load(Ordering::Relaxed) > 0 { 100 } else { 1000 };
+        c.process(Duration::from_millis(timeout)).map_err(err_str)?;
+        for (msg, start, result) in authorized_receiver.try_iter() {
+            pending_checks.fetch_sub(1, Ordering::Relaxed);
+            handle_method_call(&cr, msg, &c, start, result);
+        }
+        if let Some(idle_timeout) = idle_timeout {
+            // Keep running while in maintenance, as exiting would forget about it, and while
+            // waiting for polkit, as the caller would get no reply
+            let idle = last_call.lock().unwrap_or_else(|err| err.into_inner()).elapsed();
+            if idle >= idle_timeout
+                && ! maintenance.load(Ordering::Relaxed)
+                && pending_checks.load(Ordering::Relaxed) == 0
+            {
+                log!("exiting after {} seconds without calls", idle.as_secs());
+                return Ok(());
+            }
+        }
+    }
 }
 
 fn main() {
     match daemon() {
         Ok(()) => (),
         Err(err) => {
-            eprintln!("popsec-daemon: {}", err);
+            log!("{}", err);
             process::exit(1);
         }
     }
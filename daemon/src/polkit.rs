@@ -0,0 +1,81 @@
+use dbus::{
+    arg::{PropMap, Variant},
+    blocking::SyncConnection,
+};
+use dbus_crossroads::MethodErr;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+// Allow time for the user to respond to an authentication prompt
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+// Allow polkit to prompt the user for authentication
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+/// Checks with polkit whether callers are authorized for actions.
+///
+/// One system bus connection is shared by all checks, which may run at the same time.
+#[derive(Clone)]
+pub struct Authority {
+    c: Arc<SyncConnection>,
+}
+
+impl Authority {
+    pub fn new() -> Result<Self, dbus::Error> {
+        Ok(Self {
+            c: Arc::new(SyncConnection::new_system()?),
+        })
+    }
+
+    /// Check that the sender of a method call is authorized for an action.
+    ///
+    /// This blocks until the user has answered the authentication prompt, if any.
+    pub fn check(&self, sender: Option<&str>, action_id: &str) -> Result<(), MethodErr> {
+        let sender = sender
+            .ok_or_else(|| MethodErr::failed(&"method call has no sender"))?
+            .to_string();
+
+        let proxy = self.c.with_proxy(
+            "org.freedesktop.PolicyKit1",
+            "/org/freedesktop/PolicyKit1/Authority",
+            TIMEOUT,
+        );
+
+        let mut subject_details = PropMap::new();
+        subject_details.insert("name".to_string(), Variant(Box::new(sender)));
+        let subject = ("system-bus-name", subject_details);
+        let details: HashMap<&str, &str> = HashMap::new();
+
+        let ((authorized, _challenge, _details),): ((bool, bool, HashMap<String, String>),) = proxy
+            .method_call(
+                "org.freedesktop.PolicyKit1.Authority",
+                "CheckAuthorization",
+                (subject, action_id, details, ALLOW_USER_INTERACTION, ""),
+            )
+            .map_err(MethodErr::from)?;
+
+        if authorized {
+            Ok(())
+        } else {
+            Err(MethodErr::from(dbus::Error::new_custom(
+                "org.freedesktop.DBus.Error.AccessDenied",
+                &format!("not authorized for {}", action_id),
+            )))
+        }
+    }
+
+    /// Check on another thread, calling `f` with the outcome, so that waiting for the user does
+    /// not hold up other method calls.
+    pub fn check_async<F>(&self, sender: Option<String>, action_id: &'static str, f: F)
+        where F: FnOnce(Result<(), MethodErr>) + Send + 'static
+    {
+        let authority = self.clone();
+        thread::spawn(move || {
+            f(authority.check(sender.as_deref(), action_id));
+        });
+    }
+}
@@ -0,0 +1,110 @@
+use popsec::tpm2_totp::*;
+use std::{
+    fs,
+    io::Write,
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{config::Config, err_str, log_notices, RESEAL_JOURNAL};
+
+// Clients that stop reading are dropped instead of stalling the other clients
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Each line is either the zero-padded code followed by the seconds remaining in its window, or
+// "error" followed by a description of the error
+fn line(result: &Result<TotpCode, TotpError>) -> String {
+    match result {
+        Ok(code) => format!("{:06} {}\n", code.0, window_remaining()),
+        Err(err) => format!("error {}\n", err),
+    }
+}
+
+fn show(tpm2_totp: &Mutex<Option<Tpm2Totp>>, config: &Config, maintenance: &AtomicBool) -> Result<TotpCode, TotpError> {
+    if maintenance.load(Ordering::Relaxed) {
+        return Err(TotpError::Maintenance);
+    }
+    let mut guard = tpm2_totp.lock().unwrap_or_else(|err| err.into_inner());
+    if guard.is_none() {
+        *guard = Some(config.tpm2_totp()?.with_journal(RESEAL_JOURNAL));
+    }
+    let tpm2_totp = guard.as_mut().unwrap();
+    let result = tpm2_totp.show();
+    log_notices(tpm2_totp);
+    result
+}
+
+fn add_client(clients: &mut Vec<UnixStream>, mut client: UnixStream, text: &str) {
+    if let Err(err) = client.set_write_timeout(Some(WRITE_TIMEOUT)) {
+        log!("failed to set socket write timeout: {}", err);
+        return;
+    }
+    if client.write_all(text.as_bytes()).is_ok() {
+        clients.push(client);
+    }
+}
+
+/// Serve the current code on a unix socket, sending it on connect and again each window.
+///
+/// The TPM2 TOTP instance is shared with the dbus interface, so that both serialize their access
+/// to the TPM and see the same resealed secret.
+pub fn serve(
+    path: PathBuf,
+    config: Config,
+    tpm2_totp: Arc<Mutex<Option<Tpm2Totp>>>,
+    maintenance: Arc<AtomicBool>,
+) -> Result<(), String> {
+    let _ = fs::remove_file(&path);
+    // Create the socket with mode 0600, so that it is never reachable by other users
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    unsafe { libc::umask(umask) };
+    let listener = listener.map_err(err_str)?;
+
+    let (sender, receiver) = mpsc::channel::<UnixStream>();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => if sender.send(stream).is_err() {
+                    break;
+                },
+                Err(err) => {
+                    log!("failed to accept socket connection: {}", err);
+                }
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        let mut clients: Vec<UnixStream> = Vec::new();
+        loop {
+            let result = show(&tpm2_totp, &config, &maintenance);
+            let text = line(&result);
+            clients.retain(|mut client| client.write_all(text.as_bytes()).is_ok());
+
+            let end = Instant::now() + Duration::from_secs(window_remaining());
+            loop {
+                let now = Instant::now();
+                if now >= end {
+                    break;
+                }
+                match receiver.recv_timeout(end - now) {
+                    Ok(client) => add_client(&mut clients, client, &text),
+                    Err(mpsc::RecvTimeoutError::Timeout) => break,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,23 @@
+// Check a code entered at login against the TPM, for use with pam_exec:
+//
+//     auth required pam_exec.so quiet expose_authtok /usr/lib/popsec/pam-current-code
+//
+// pam_exec passes the entered code on stdin, and the exit status tells whether it matched
+use popsec::tpm2_totp::pam_current_code;
+use std::{
+    io::{self, Read},
+    process,
+};
+
+fn main() {
+    let mut entered = String::new();
+    if io::stdin().read_to_string(&mut entered).is_err() {
+        process::exit(1);
+    }
+    // pam_exec terminates the token with a NUL byte
+    let entered = entered.trim_end_matches('\0').trim();
+    match pam_current_code() {
+        Some(code) if code.verify(entered) => process::exit(0),
+        _ => process::exit(1),
+    }
+}
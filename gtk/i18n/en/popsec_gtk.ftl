@@ -1,15 +1,110 @@
+audit-mode = Audit Mode
 cancel = Cancel
+daemon-not-running = The security daemon is not running. Install and start popsec-daemon.
+daemon-version-mismatch = The security daemon does not match this version of the settings. Please update popsec-daemon.
+deployed-mode = Deployed Mode
 disabled = Disabled
 enabled = Enabled
+no = No
 ok = Ok
+qr-ec = Error correction, higher keeps printed codes scannable when damaged
+qr-ec-high = High error correction
+qr-ec-low = Low error correction
+qr-ec-medium = Medium error correction
+qr-ec-quartile = Quartile error correction
+retry = Retry
 secure-boot = Secure Boot
+secure-boot-enroll = Default keys are not enrolled
+secure-boot-enroll-button = Enroll
+secure-boot-enroll-confirm = Enroll the Pop!_OS default Secure Boot keys? This takes the firmware out of setup mode.
+secure-boot-keys-changed = Secure Boot keys changed since enrollment
+secure-boot-keys-changed-warning = The keys differ from when the TPM2 TOTP secret was set up. If you did not change them, they may have been tampered with.
+secure-boot-not-efi = Not an EFI system
+secure-boot-os-keys = Pop!_OS Keys Enrolled
 secure-boot-state = Secure Boot State
 setup-mode = Setup Mode
 tpm = Trusted Platform Module
+tpm-advanced = Advanced
+tpm-banks = PCR Banks
+tpm-busy = The TPM is busy or out of memory. Close other applications using the TPM, then try again.
+tpm-authenticity = TPM Authenticity
+tpm-authenticity-unverified = Unverified
+tpm-authenticity-verified = Verified
+tpm-clock = Clock
+tpm-clock-unsafe = TPM clock unreliable
+tpm-clock-unsafe-warning = The TPM lost power without shutting down, so its clock could have gone backwards and codes may be unreliable
+tpm-diagnostics = Diagnostics for bug reports
+tpm-diagnostics-copy = Copy
+tpm-not-started = The TPM has not been started. Restart the computer to start it.
+tpm-reset-count = Reset Count
+tpm-restart-count = Restart Count
+tpm-seconds = { $seconds } s
+tpm-type = Type
+tpm-type-discrete = Discrete TPM
+tpm-type-firmware = Firmware TPM
+tpm-type-unknown = Unknown
+tpm-type-virtual = Virtual TPM
+tpm-uptime = Uptime
 tpm2-totp = TPM2 TOTP
+tpm2-totp-auto-refresh = Automatic Refresh
+tpm2-totp-changes = Changed since the secret was sealed: { $changes }
+tpm2-totp-checksum = Secret checksum: { $checksum }
+tpm2-totp-code = TPM2 TOTP code
+tpm2-totp-copied = Link copied. As it contains the secret, it will be cleared from the clipboard in { $seconds } seconds.
+tpm2-totp-copy-link = Copy Link
+tpm2-totp-countdown-ring = Show Countdown as a Ring
+tpm2-totp-expires = Code expires in { $seconds } seconds
+tpm2-totp-corrupt = The stored secret is corrupted. Remove it with tpm2-totp clean, then set it up again.
+tpm2-totp-index-in-use = The TPM storage for the secret is used by another application. Choose a different NV index to set up the secret.
+tpm2-totp-hide-code = Hide Code
+tpm2-totp-hide-code-description = Show dots in place of the code until it is hovered or revealed
 tpm2-totp-init = Secret is not set up
 tpm2-totp-init-button = Set Up
+tpm2-totp-init-cancelled = Cancelled. The secret may still be set up in the background, in which case it must be set up again to show its QR code.
+tpm2-totp-init-progress = Setting up the secret…
+tpm2-totp-qr-failed = The QR code could not be shown. Enter this link in your authenticator app instead: { $url }
 tpm2-totp-reseal = System state has changed
 tpm2-totp-reseal-button = Reseal
+tpm2-totp-reseal-cancelled = Cancelled. The secret may still be resealed in the background.
+tpm2-totp-reseal-progress = Resealing the secret…
+tpm2-totp-reveal = Reveal Code
+tpm2-totp-secret-timeout = To keep the secret from being seen, this window closes after { $seconds } seconds without activity.
+tpm2-totp-slot = Secret at NV index { $index }
+tpm2-totp-slot-pcrs = Bound to PCR { $pcrs }
+tpm2-totp-strength = Strength
+tpm2-totp-strength-weak = Too weak for a recovery password
+tpm2-totp-maintenance = Maintenance in progress, the code will be shown once it is finished
+tpm2-totp-manual-key = Or enter this key in your authenticator app:
+tpm2-totp-measured-boot-inactive = The firmware does not appear to measure the boot, so the secret would not detect changes to it. Set up the secret anyway?
+tpm2-totp-no-recovery-password = The secret was set up without a recovery password and cannot be resealed
 tpm2-totp-password = Password
+tpm2-totp-phase-delete-key = Removing the old secret from the TPM…
+tpm2-totp-phase-generate-key = Generating the secret…
+tpm2-totp-phase-load-key = Loading the secret…
+tpm2-totp-phase-reseal = Sealing the secret to the system state…
+tpm2-totp-phase-store-key = Storing the secret in the TPM…
+tpm2-totp-pcr = PCR { $pcr }
+tpm2-totp-pcr-0 = Firmware
+tpm2-totp-pcr-1 = Firmware Configuration
+tpm2-totp-pcr-1-warning = Changing firmware settings, such as the boot order, will require resealing
+tpm2-totp-pcr-2 = Option ROMs
+tpm2-totp-pcr-4 = Boot Loader
+tpm2-totp-pcr-4-warning = Operating system and boot loader updates will require resealing
+tpm2-totp-pcr-7 = Secure Boot State
+tpm2-totp-pcr-state = Bound State
+tpm2-totp-pcr-state-changed = Changed, reseal recommended
+tpm2-totp-pcr-state-matches = Matches current
 tpm2-totp-confirm = Confirm
+tpm2-totp-invalid-password = Password contains invalid characters
+tpm2-totp-notification = Show Code in Notifications
+tpm2-totp-notification-warning = The code will be visible on screen outside of this window
+tpm2-totp-nv-locked = The secret is write locked by the firmware and cannot be resealed until the next restart
+tpm2-totp-nv-space-full = The TPM has no space left for the secret. Clean up unused NV indices, or contact your hardware vendor.
+tpm2-totp-nv-space-low = The TPM is almost out of storage, so setting up the secret may fail. Try anyway?
+tpm2-totp-verify = Enter the code shown by your authenticator app to confirm it was set up correctly
+tpm2-totp-verify-button = Verify
+tpm2-totp-verify-cancelled = The authenticator app was not verified. If its codes do not match, set it up again.
+tpm2-totp-verify-code = Code
+tpm2-totp-verify-mismatch = The code does not match, check that the QR code was scanned correctly
+unknown = Unknown
+yes = Yes
@@ -1,32 +1,87 @@
 use cascade::cascade;
 use chrono::prelude::*;
-use gtk::prelude::*;
+use gtk::{
+    atk::{self, prelude::*},
+    prelude::*,
+};
 use i18n_embed::DesktopLanguageRequester;
 use libhandy::prelude::*;
 use popsec::dbus::{
     Client as DbusClient,
     Error as DbusError,
 };
+use popsec::{
+    otpauth,
+    secure_boot::{self, SecureBootState},
+};
 use popsec::tpm2_totp::{
+    HashAlg,
+    Phase,
+    Progress,
+    SlotCode,
     TotpCode,
     TotpError,
     TotpPass,
-    TotpSecret,
-    Tpm2Totp
+    Tpm2Totp,
+    TpmType,
 };
 use std::{
+    cell::{Cell, RefCell},
+    f64::consts::{FRAC_PI_2, PI},
     fs,
+    path::Path,
+    rc::Rc,
     str,
     sync::{
         Arc,
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicBool, AtomicUsize, Ordering},
     },
     thread,
     time
 };
+use zeroize::Zeroizing;
 
 mod localize;
 
+const SETTINGS_SCHEMA: &str = "com.system76.PopSec";
+
+// Time to wait for more refresh requests before refreshing
+const REFRESH_DEBOUNCE: time::Duration = time::Duration::from_millis(250);
+
+// Seconds into a new TOTP window during which the previous code is still shown
+const PREVIOUS_CODE_SECONDS: f64 = 5.0;
+
+// PCRs that can be selected when setting up the TOTP secret
+const TPM2_TOTP_PCRS: &[u32] = &[0, 1, 2, 4, 7];
+
+// Seconds until a copied otpauth URL, which contains the secret, is cleared from the clipboard
+const CLIPBOARD_CLEAR_SECONDS: u32 = 30;
+
+// Seconds without user activity after which a dialog showing the secret is closed
+const SECRET_DIALOG_TIMEOUT_SECONDS: u64 = 60;
+
+// ID of the notification mirroring the current code, which is replaced each window
+const CODE_NOTIFICATION_ID: &str = "tpm2-totp-code";
+
+// Seconds a hidden code stays revealed after hovering it or pressing reveal
+const CODE_REVEAL_SECONDS: u32 = 10;
+
+// Diameter in pixels of the countdown ring shown instead of the progress bar
+const COUNTDOWN_RING_SIZE: i32 = 24;
+
+// Lowest zxcvbn score, out of 4, accepted for a new recovery password
+const MIN_PASSWORD_SCORE: u8 = 3;
+
+// Settings are optional, as the schema is not available when running from the build directory
+fn settings() -> Option<gio::Settings> {
+    gio::SettingsSchemaSource::default()?.lookup(SETTINGS_SCHEMA, true)?;
+    Some(gio::Settings::new(SETTINGS_SCHEMA))
+}
+
+fn tpm2_totp_pcrs() -> u32 {
+    settings().map_or(Tpm2Totp::PCRS, |settings| settings.uint("tpm2-totp-pcrs"))
+}
+
 pub fn localize() {
     let localizer = crate::localize::localizer();
     let requested_languages = DesktopLanguageRequester::requested_languages();
@@ -83,46 +138,394 @@ fn settings_list_box<C: ContainerExt>(container: &C, title: &str) -> gtk::ListBo
     list_box
 }
 
-fn secure_boot<C: ContainerExt>(container: &C) {
-    let list_box = settings_list_box(container, &fl!("secure-boot"));
-
-    let secure_boot = fs::read(
-        "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c"
-    ).ok().map_or(false, |x| x.get(4).map_or(false, |x| *x > 0));
-
-    let setup_mode = fs::read(
-        "/sys/firmware/efi/efivars/SetupMode-8be4df61-93ca-11d2-aa0d-00e098032b8c"
-    ).ok().map_or(false, |x| x.get(4).map_or(false, |x| *x > 0));
-
-    let label = label_row(&list_box, &fl!("secure-boot-state"));
-    label.set_text(&if secure_boot {
-        if setup_mode {
+fn secure_boot_text(state: &SecureBootState) -> String {
+    if state.secure_boot {
+        if state.setup_mode {
             fl!("setup-mode")
         } else {
             fl!("enabled")
         }
     } else {
         fl!("disabled")
+    }
+}
+
+fn enabled_text(enabled: bool) -> String {
+    if enabled {
+        fl!("enabled")
+    } else {
+        fl!("disabled")
+    }
+}
+
+fn secure_boot<C: ContainerExt>(container: &C) {
+    let list_box = settings_list_box(container, &fl!("secure-boot"));
+
+    let label = label_row(&list_box, &fl!("secure-boot-state"));
+
+    // Legacy BIOS systems have no Secure Boot, which is different from it being disabled
+    if ! secure_boot::efi_supported() {
+        label.set_text(&fl!("secure-boot-not-efi"));
+        return;
+    }
+
+    // Audit and deployed mode are only shown by firmware that supports them
+    let initial = secure_boot::read_state().unwrap_or_default();
+    let audit_mode_label = initial.audit_mode.map(|_| label_row(&list_box, &fl!("audit-mode")));
+    let deployed_mode_label = initial.deployed_mode.map(|_| label_row(&list_box, &fl!("deployed-mode")));
+    // Only shown when the package ships the certificate to look for
+    let os_keys_label = if Path::new(secure_boot::OS_DB_CERT).is_file() {
+        Some(label_row(&list_box, &fl!("secure-boot-os-keys")))
+    } else {
+        None
+    };
+
+    // In setup mode, no platform key is enrolled and the default keys can be enrolled, if the
+    // package ships them
+    let keys_available = secure_boot::default_keys_available();
+    let enroll_button = cascade! {
+        gtk::Button::with_label(&fl!("secure-boot-enroll-button"));
+        ..set_valign(gtk::Align::Center);
+    };
+    let enroll_row = cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("secure-boot-enroll")));
+        ..add(&enroll_button);
+        ..set_no_show_all(true);
+    };
+    list_box.add(&enroll_row);
+
+    // Compared by the daemon with the keys when the TPM2 TOTP secret was set up
+    let keys_changed_row = cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("secure-boot-keys-changed")));
+        ..set_subtitle(Some(&fl!("secure-boot-keys-changed-warning")));
+        ..add(&gtk::Image::from_icon_name(Some("dialog-warning-symbolic"), gtk::IconSize::Button));
+        ..set_no_show_all(true);
+    };
+    list_box.add(&keys_changed_row);
+
+    // Checking the keys calls the daemon and reads the signature databases, which can be slow, so
+    // it is done on another thread that sends whether they changed and whether the OS keys are
+    // enrolled
+    let (keys_sender, keys_receiver) = glib::MainContext::channel::<(bool, Option<bool>)>(glib::PRIORITY_DEFAULT);
+    keys_receiver.attach(None, move |(keys_changed, os_keys)| {
+        keys_changed_row.set_visible(keys_changed);
+        if keys_changed {
+            keys_changed_row.show_all();
+        }
+        if let Some(os_keys_label) = &os_keys_label {
+            os_keys_label.set_text(&match os_keys {
+                Some(true) => fl!("yes"),
+                Some(false) => fl!("no"),
+                None => fl!("unknown"),
+            });
+        }
+        glib::Continue(true)
+    });
+
+    let update = {
+        let label = label.clone();
+        let enroll_row = enroll_row.clone();
+        Rc::new(move || {
+            let state = secure_boot::read_state().unwrap_or_default();
+            label.set_text(&secure_boot_text(&state));
+            let can_enroll = state.setup_mode && keys_available;
+            enroll_row.set_visible(can_enroll);
+            if can_enroll {
+                enroll_row.show_all();
+            }
+            if let Some(audit_mode_label) = &audit_mode_label {
+                audit_mode_label.set_text(&enabled_text(state.audit_mode.unwrap_or(false)));
+            }
+            if let Some(deployed_mode_label) = &deployed_mode_label {
+                deployed_mode_label.set_text(&enabled_text(state.deployed_mode.unwrap_or(false)));
+            }
+            let keys_sender = keys_sender.clone();
+            thread::spawn(move || {
+                let keys_changed = DbusClient::new()
+                    .and_then(|client| client.secure_boot_keys_changed())
+                    .unwrap_or_else(|err| {
+                        eprintln!("failed to check for Secure Boot key changes: {:?}", err);
+                        false
+                    });
+                let os_keys = secure_boot::os_keys_enrolled()
+                    .map_err(|err| eprintln!("failed to check for Pop!_OS keys: {}", err))
+                    .ok()
+                    .flatten();
+                let _ = keys_sender.send((keys_changed, os_keys));
+            });
+        })
+    };
+    update();
+
+    {
+        let update = update.clone();
+        enroll_button.connect_clicked(move |button| {
+            let dialog = gtk::MessageDialog::new(
+                None::<&gtk::Window>,
+                gtk::DialogFlags::MODAL,
+                gtk::MessageType::Question,
+                gtk::ButtonsType::OkCancel,
+                &fl!("secure-boot-enroll-confirm")
+            );
+            let response = dialog.run();
+            dialog.hide();
+            if response != gtk::ResponseType::Ok {
+                return;
+            }
+
+            button.set_sensitive(false);
+            let result = DbusClient::new().and_then(|client| client.secure_boot_enroll_default_keys());
+            if let Err(err) = result {
+                eprintln!("failed to enroll default keys: {:?}", err);
+                error_dialog(&error_text(&err));
+            }
+            button.set_sensitive(true);
+            update();
+        });
+    }
+
+    // Update the state when the variables change while the panel is open
+    let mut monitors = Vec::new();
+    for name in secure_boot::STATE_VARIABLES {
+        let path = secure_boot::variable_path(name, secure_boot::EFI_GLOBAL_VARIABLE);
+        if fs::metadata(&path).is_err() {
+            continue;
+        }
+        let file = gio::File::for_path(&path);
+        match file.monitor_file(gio::FileMonitorFlags::NONE, None::<&gio::Cancellable>) {
+            Ok(monitor) => {
+                let update = update.clone();
+                monitor.connect_changed(move |_, _, _, _| update());
+                monitors.push(monitor);
+            },
+            Err(err) => {
+                eprintln!("failed to monitor {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    // The monitors stop when dropped, so keep them alive as long as the label
+    label.connect_destroy(move |_| {
+        let _ = &monitors;
+    });
+}
+
+// Dots in place of the digits of a code, keeping its grouping
+fn masked_code(code: &str) -> String {
+    code.chars().map(|c| if c.is_ascii_digit() { '\u{2022}' } else { c }).collect()
+}
+
+// A circle that empties as the current window runs out, like in authenticator apps. It draws the
+// fraction of the window that has passed, as set before queueing a redraw.
+fn countdown_ring(fraction: Rc<Cell<f64>>) -> gtk::DrawingArea {
+    let area = cascade! {
+        gtk::DrawingArea::new();
+        ..set_size_request(COUNTDOWN_RING_SIZE, COUNTDOWN_RING_SIZE);
+        ..set_no_show_all(true);
+        ..set_valign(gtk::Align::Center);
+        ..set_visible(false);
+    };
+    area.connect_draw(move |area, cr| {
+        let width = f64::from(area.allocated_width());
+        let height = f64::from(area.allocated_height());
+        let line_width = 3.0;
+        let radius = (width.min(height) - line_width) / 2.0;
+        let color = area.style_context().color(gtk::StateFlags::NORMAL);
+        cr.set_line_width(line_width);
+
+        // The whole circle faintly, and the time remaining on top of it, starting from the top
+        cr.set_source_rgba(color.red, color.green, color.blue, color.alpha * 0.2);
+        cr.arc(width / 2.0, height / 2.0, radius, 0.0, 2.0 * PI);
+        let _ = cr.stroke();
+
+        let remaining = (1.0 - fraction.get()).clamp(0.0, 1.0);
+        let start = -FRAC_PI_2;
+        cr.set_source_rgba(color.red, color.green, color.blue, color.alpha);
+        cr.arc(width / 2.0, height / 2.0, radius, start, start + remaining * 2.0 * PI);
+        let _ = cr.stroke();
+
+        gtk::Inhibit(false)
+    });
+    area
+}
+
+fn error_text(error: &DbusError) -> String {
+    match error {
+        DbusError::Totp(TotpError::InvalidPassword) => fl!("tpm2-totp-invalid-password"),
+        DbusError::Totp(TotpError::NvSpaceFull) => fl!("tpm2-totp-nv-space-full"),
+        DbusError::Totp(TotpError::NotStarted) => fl!("tpm-not-started"),
+        DbusError::Totp(TotpError::NvLocked) => fl!("tpm2-totp-nv-locked"),
+        DbusError::Totp(TotpError::IndexInUse) => fl!("tpm2-totp-index-in-use"),
+        DbusError::Totp(TotpError::CorruptBlob) => fl!("tpm2-totp-corrupt"),
+        DbusError::Totp(TotpError::Maintenance) => fl!("tpm2-totp-maintenance"),
+        DbusError::Totp(TotpError::TpmBusy) => fl!("tpm-busy"),
+        DbusError::Call(_, err) if err.name() == Some("org.freedesktop.DBus.Error.ServiceUnknown") => {
+            fl!("daemon-not-running")
+        },
+        DbusError::VersionMismatch(_, _) => fl!("daemon-version-mismatch"),
+        _ => format!("{}", error),
+    }
+}
+
+// Render a QR code of the otpauth URL for scanning by an authenticator app
+fn qr_pixbuf(url: &str, ec_level: qrcode::EcLevel) -> Result<gdk_pixbuf::Pixbuf, String> {
+    let qr = qrcode::QrCode::with_error_correction_level(url, ec_level).map_err(|err| err.to_string())?;
+    // The SVG encodes the secret as well
+    let svg = Zeroizing::new(qr.render::<qrcode::render::svg::Color>().build());
+    let bytes = glib::Bytes::from(svg.as_bytes());
+    let stream = gio::MemoryInputStream::from_bytes(&bytes);
+    gdk_pixbuf::Pixbuf::from_stream(&stream, None::<&gio::Cancellable>)
+        .map_err(|err| err.to_string())
+}
+
+fn phase_text(phase: Phase) -> String {
+    match phase {
+        Phase::LoadKey => fl!("tpm2-totp-phase-load-key"),
+        Phase::GenerateKey => fl!("tpm2-totp-phase-generate-key"),
+        Phase::Reseal => fl!("tpm2-totp-phase-reseal"),
+        Phase::DeleteKey => fl!("tpm2-totp-phase-delete-key"),
+        Phase::StoreKey => fl!("tpm2-totp-phase-store-key"),
+    }
+}
+
+// Run a slow TPM operation on another thread, with a dialog that lets the user stop waiting for
+// it. The operation itself cannot be aborted, so it still completes in the background. Returns
+// `None` if the user cancelled.
+fn tpm_progress_dialog<T, F>(text: &str, f: F) -> Option<T>
+    where T: Send + 'static, F: FnOnce() -> T + Send + 'static
+{
+    let progress_bar = cascade! {
+        gtk::ProgressBar::new();
+        ..set_show_text(true);
+        ..set_text(Some(""));
+    };
+    let dialog = cascade! {
+        gtk::Dialog::new();
+        ..add_button(&fl!("cancel"), gtk::ResponseType::Cancel);
+        ..content_area().add(&cascade! {
+            gtk::Box::new(gtk::Orientation::Vertical, 12);
+            ..set_margin_top(12);
+            ..set_margin_bottom(12);
+            ..set_margin_start(12);
+            ..set_margin_end(12);
+            ..add(&gtk::Label::new(Some(text)));
+            ..add(&progress_bar);
+        });
+    };
+    dialog.show_all();
+
+    // The daemon signals each phase as it starts, which are received on a connection of their own
+    // as the call blocks the other one
+    let done = Arc::new(AtomicBool::new(false));
+    let (ready_sender, ready_receiver) = std::sync::mpsc::channel();
+    let (progress_sender, progress_receiver) = glib::MainContext::channel::<Progress>(glib::PRIORITY_DEFAULT);
+    progress_receiver.attach(None, move |progress| {
+        let steps = progress.steps.max(1);
+        progress_bar.set_fraction(f64::from(progress.step.saturating_sub(1)) / f64::from(steps));
+        progress_bar.set_text(Some(&phase_text(progress.phase)));
+        glib::Continue(true)
+    });
+    {
+        let done = done.clone();
+        thread::spawn(move || {
+            let client = DbusClient::new().and_then(|client| {
+                client.watch_tpm2_totp_progress()?;
+                Ok(client)
+            });
+            let _ = ready_sender.send(());
+            let client = match client {
+                Ok(ok) => ok,
+                Err(err) => {
+                    eprintln!("failed to watch progress: {:?}", err);
+                    return;
+                }
+            };
+            while ! done.load(Ordering::Relaxed) {
+                if let Some(progress) = client.tpm2_totp_progress(250) {
+                    if progress_sender.send(progress).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    let result = Rc::new(RefCell::new(None));
+    let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+    {
+        let dialog = dialog.clone();
+        let result = result.clone();
+        receiver.attach(None, move |value| {
+            *result.borrow_mut() = Some(value);
+            dialog.response(gtk::ResponseType::Ok);
+            glib::Continue(false)
+        });
+    }
+    thread::spawn(move || {
+        // Start once progress is watched, so that no phase is missed
+        let _ = ready_receiver.recv_timeout(time::Duration::from_secs(1));
+        // After cancelling, nothing is waiting for the result anymore
+        let _ = sender.send(f());
     });
+
+    let response = dialog.run();
+    dialog.hide();
+    done.store(true, Ordering::Relaxed);
+    if response == gtk::ResponseType::Ok {
+        result.borrow_mut().take()
+    } else {
+        None
+    }
 }
 
-fn otpauth_url(secret: &TotpSecret) -> String {
-    let description = match sys_info::hostname() {
-        Ok(hostname) => format!("{} TPM2-TOTP", hostname),
-        Err(_) => format!("TPM2-TOTP"),
-    };
-    let secret_b32 = base32::encode(
-        base32::Alphabet::RFC4648 { padding: false },
-        &secret.0
-    );
-    format!(
-        "otpauth://totp/{}?secret={}",
-        description,
-        secret_b32
-    )
+fn error_dialog(text: &str) {
+    cascade! {
+        gtk::MessageDialog::new(
+            None::<&gtk::Window>,
+            gtk::DialogFlags::MODAL,
+            gtk::MessageType::Error,
+            gtk::ButtonsType::Ok,
+            text
+        );
+        ..run();
+        ..hide();
+    };
+}
+
+// Error dialog offering to try again, returning true if the user chose to
+fn retry_dialog(text: &str) -> bool {
+    let dialog = cascade! {
+        gtk::MessageDialog::new(
+            None::<&gtk::Window>,
+            gtk::DialogFlags::MODAL,
+            gtk::MessageType::Error,
+            gtk::ButtonsType::None,
+            text
+        );
+        ..add_button(&fl!("cancel"), gtk::ResponseType::Cancel);
+        ..add_button(&fl!("retry"), gtk::ResponseType::Ok);
+        ..set_default_response(gtk::ResponseType::Ok);
+    };
+    let response = dialog.run();
+    dialog.hide();
+    response == gtk::ResponseType::Ok
+}
+
+fn pcr_title(pcr: u32) -> String {
+    match pcr {
+        0 => fl!("tpm2-totp-pcr-0"),
+        1 => fl!("tpm2-totp-pcr-1"),
+        2 => fl!("tpm2-totp-pcr-2"),
+        4 => fl!("tpm2-totp-pcr-4"),
+        7 => fl!("tpm2-totp-pcr-7"),
+        _ => fl!("tpm2-totp-pcr", pcr = pcr),
+    }
 }
 
-fn tpm_password_dialog(confirm: bool) -> Option<String> {
+// When `pcrs` is provided, the PCRs to bind to can be chosen, and the selection is written back
+fn tpm_password_dialog(confirm: bool, mut pcrs: Option<&mut u32>) -> Option<String> {
     let entry = cascade! {
         gtk::Entry::new();
         ..set_valign(gtk::Align::Center);
@@ -142,28 +545,100 @@ fn tpm_password_dialog(confirm: bool) -> Option<String> {
             ..add(&entry);
         });
     };
+    let strength_bar = cascade! {
+        gtk::LevelBar::for_interval(0.0, 4.0);
+        ..set_mode(gtk::LevelBarMode::Discrete);
+        ..set_valign(gtk::Align::Center);
+        ..set_hexpand(true);
+    };
+    let strength_row = cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm2-totp-strength")));
+        ..add(&strength_bar);
+    };
     if confirm {
+        list_box.add(&strength_row);
         list_box.add(&cascade! {
             libhandy::ActionRow::new();
             ..set_title(Some(&fl!("tpm2-totp-confirm")));
             ..add(&entry_confirm);
         });
     }
+    let mut pcr_checks = Vec::new();
+    if let Some(pcrs) = &pcrs {
+        for &pcr in TPM2_TOTP_PCRS {
+            let check = cascade! {
+                gtk::CheckButton::new();
+                ..set_active(**pcrs & (1 << pcr) != 0);
+                ..set_valign(gtk::Align::Center);
+            };
+            let row = cascade! {
+                libhandy::ActionRow::new();
+                ..set_title(Some(&pcr_title(pcr)));
+                ..add_prefix(&check);
+                ..set_activatable_widget(Some(&check));
+            };
+            match pcr {
+                1 => row.set_subtitle(Some(&fl!("tpm2-totp-pcr-1-warning"))),
+                4 => row.set_subtitle(Some(&fl!("tpm2-totp-pcr-4-warning"))),
+                _ => (),
+            }
+            list_box.add(&row);
+            pcr_checks.push((pcr, check));
+        }
+    }
     let dialog = cascade! {
         gtk::Dialog::new();
         ..add_button(&fl!("cancel"), gtk::ResponseType::Cancel);
-        ..add_button(&fl!("ok"), gtk::ResponseType::Ok);
         ..content_area().add(&list_box);
     };
+    let ok_button = dialog.add_button(&fl!("ok"), gtk::ResponseType::Ok);
     dialog.show_all();
 
+    // OK stays insensitive until a new password is strong enough and, when choosing PCRs, at least
+    // one is selected
+    let strong = Rc::new(Cell::new(! confirm));
+    let update_ok = {
+        let ok_button = ok_button.clone();
+        let pcr_checks = pcr_checks.clone();
+        let strong = strong.clone();
+        Rc::new(move || {
+            let any_pcr = pcr_checks.is_empty() || pcr_checks.iter().any(|(_, check)| check.is_active());
+            ok_button.set_sensitive(strong.get() && any_pcr);
+        })
+    };
+    update_ok();
+    for (_, check) in &pcr_checks {
+        let update_ok = update_ok.clone();
+        check.connect_toggled(move |_| update_ok());
+    }
+
+    // A weak recovery password undermines the secret, so new ones must reach a minimum strength
+    if confirm {
+        let account = otpauth::account();
+        let update_ok = update_ok.clone();
+        entry.connect_changed(move |entry| {
+            let score = zxcvbn::zxcvbn(&entry.text(), &[&account])
+                .map_or(0, |entropy| entropy.score());
+            strength_bar.set_value(f64::from(score));
+            strength_row.set_subtitle(if score < MIN_PASSWORD_SCORE {
+                Some(&fl!("tpm2-totp-strength-weak"))
+            } else {
+                None
+            });
+            strong.set(score >= MIN_PASSWORD_SCORE);
+            update_ok();
+        });
+    }
+
     {
         let dialog = dialog.clone();
         let entry_confirm = entry_confirm.clone();
+        let ok_button = ok_button.clone();
         entry.connect_activate(move |_| {
             if confirm {
                 entry_confirm.grab_focus();
-            } else {
+            } else if ok_button.is_sensitive() {
                 dialog.response(gtk::ResponseType::Ok);
             }
         });
@@ -171,13 +646,25 @@ fn tpm_password_dialog(confirm: bool) -> Option<String> {
 
     {
         let dialog = dialog.clone();
+        let ok_button = ok_button.clone();
         entry_confirm.connect_activate(move |_| {
-            if confirm {
+            if confirm && ok_button.is_sensitive() {
                 dialog.response(gtk::ResponseType::Ok);
             }
         });
     }
 
+    // Do not leave the password in the entries once the dialog is gone, however it was closed.
+    // The entry buffer overwrites deleted text, so this also scrubs its memory.
+    {
+        let entry = entry.clone();
+        let entry_confirm = entry_confirm.clone();
+        dialog.connect_hide(move |_| {
+            entry.set_text("");
+            entry_confirm.set_text("");
+        });
+    }
+
     let res = loop {
         entry.set_text("");
         entry_confirm.set_text("");
@@ -186,11 +673,81 @@ fn tpm_password_dialog(confirm: bool) -> Option<String> {
         if dialog.run() != gtk::ResponseType::Ok {
             break None;
         }
+        let selected = pcr_checks.iter()
+            .filter(|(_, check)| check.is_active())
+            .fold(0, |mask, (pcr, _)| mask | (1 << *pcr));
         if !confirm || entry.text() == entry_confirm.text() {
+            if let Some(pcrs) = &mut pcrs {
+                **pcrs = selected;
+            }
             break Some(entry.text().to_string());
         }
     };
     dialog.hide();
+    // Releases the entries, rather than keeping them until the dialog is finalized
+    unsafe { dialog.destroy(); }
+
+    res
+}
+
+// Ask for the code from the authenticator app, to confirm the QR code was scanned correctly
+fn tpm_verify_dialog(client: &DbusClient) -> bool {
+    let entry = cascade! {
+        gtk::Entry::new();
+        ..set_valign(gtk::Align::Center);
+        ..set_input_purpose(gtk::InputPurpose::Digits);
+        ..set_max_length(6);
+    };
+    let error_label = cascade! {
+        gtk::Label::new(None);
+        ..set_no_show_all(true);
+        ..set_visible(false);
+        ..style_context().add_class("error");
+    };
+    let list_box = cascade! {
+        gtk::ListBox::new();
+        ..set_selection_mode(gtk::SelectionMode::None);
+        ..add(&cascade! {
+            libhandy::ActionRow::new();
+            ..set_title(Some(&fl!("tpm2-totp-verify-code")));
+            ..add(&entry);
+        });
+    };
+    let dialog = cascade! {
+        gtk::Dialog::new();
+        ..add_button(&fl!("cancel"), gtk::ResponseType::Cancel);
+        ..add_button(&fl!("tpm2-totp-verify-button"), gtk::ResponseType::Ok);
+        ..content_area().add(&gtk::Label::new(Some(&fl!("tpm2-totp-verify"))));
+        ..content_area().add(&list_box);
+        ..content_area().add(&error_label);
+    };
+    dialog.show_all();
+
+    {
+        let dialog = dialog.clone();
+        entry.connect_activate(move |_| dialog.response(gtk::ResponseType::Ok));
+    }
+
+    let res = loop {
+        entry.grab_focus();
+
+        if dialog.run() != gtk::ResponseType::Ok {
+            break false;
+        }
+        match client.tpm2_totp_show() {
+            Ok(code) => if code.verify(&entry.text()) {
+                break true;
+            } else {
+                error_label.set_text(&fl!("tpm2-totp-verify-mismatch"));
+            },
+            Err(err) => {
+                error_label.set_text(&error_text(&err));
+            }
+        }
+        error_label.set_visible(true);
+        entry.set_text("");
+    };
+    dialog.hide();
 
     res
 }
@@ -198,32 +755,110 @@ fn tpm_password_dialog(confirm: bool) -> Option<String> {
 fn tpm<C: ContainerExt>(container: &C) {
     let list_box = settings_list_box(container, &fl!("tpm"));
 
-    let refresh = Arc::new(AtomicBool::new(false));
+    // Counts refresh requests, so that the polling thread can tell when new ones arrive
+    let refresh = Arc::new(AtomicUsize::new(0));
+
+    // Polling pauses while this section is not on screen, or automatic refresh is turned off
+    let mapped = Arc::new(AtomicBool::new(false));
+    let auto_refresh = Arc::new(AtomicBool::new(
+        settings().map_or(true, |settings| settings.boolean("tpm2-totp-auto-refresh"))
+    ));
+
+    // Seconds between updates of the progress bar, which low power setups may want to raise
+    let poll_interval = settings().map_or(1, |settings| settings.uint("tpm2-totp-poll-interval")).max(1);
 
     enum Message {
         Code(TotpCode),
         Error(DbusError),
+        // Every secret, when there is more than one
+        Slots(Vec<SlotCode>),
+        Changes(Vec<String>),
+        ResealBlocked(Option<String>),
+        // Whether the PCRs match those the secret was sealed under, when it could be checked
+        PcrState(Option<bool>),
+        // Whether the TPM reports that its clock could have gone backwards
+        ClockUnsafe(bool),
         Timeout(f64),
     }
+    // Tells the polling thread to exit once this section is destroyed
+    let stop = Arc::new(AtomicBool::new(false));
+
     let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
     {
         let refresh = refresh.clone();
+        let mapped = mapped.clone();
+        let auto_refresh = auto_refresh.clone();
+        let stop = stop.clone();
         thread::spawn(move || {
             let client = DbusClient::new().unwrap(); // TODO: error handling
-            loop {
+            let paused = || {
+                ! mapped.load(Ordering::Relaxed) || ! auto_refresh.load(Ordering::Relaxed)
+            };
+            let stopped = || stop.load(Ordering::Relaxed);
+            // Requests arriving in quick succession are merged into a single refresh
+            let mut seen = 0;
+            let mut refresh_requested = || {
+                if refresh.load(Ordering::Relaxed) == seen {
+                    return false;
+                }
+                thread::sleep(REFRESH_DEBOUNCE);
+                seen = refresh.load(Ordering::Relaxed);
+                true
+            };
+            while ! stopped() {
+                // Wait until polling resumes, or a refresh is requested
+                while paused() && ! refresh_requested() {
+                    if stopped() {
+                        return;
+                    }
+                    thread::sleep(time::Duration::new(poll_interval.into(), 0));
+                }
+
                 let result = client.tpm2_totp_show();
                 match result {
                     Ok(ok) => {
                         sender.send(Message::Code(ok))
                             .expect("failed to send tpm2-totp code");
+
+                        let slots = client.tpm2_totp_show_all().unwrap_or_default();
+                        sender.send(Message::Slots(if slots.len() > 1 { slots } else { Vec::new() }))
+                            .expect("failed to send tpm2-totp slots");
                     },
                     Err(err) => {
+                        // Check that resealing is possible before offering it
+                        let changes = if let DbusError::Totp(TotpError::SystemStateChanged) = err {
+                            let blocked = match (
+                                client.tpm2_totp_has_recovery_password(),
+                                client.tpm2_totp_nv_locked()
+                            ) {
+                                (Ok(false), _) => Some(fl!("tpm2-totp-no-recovery-password")),
+                                (_, Ok(true)) => Some(fl!("tpm2-totp-nv-locked")),
+                                _ => None,
+                            };
+                            sender.send(Message::ResealBlocked(blocked))
+                                .expect("failed to send tpm2-totp reseal state");
+                            // Hint at what changed, when the daemon can tell
+                            Some(client.tpm2_totp_changes().unwrap_or_default())
+                        } else {
+                            None
+                        };
                         sender.send(Message::Error(err))
                             .expect("failed to send tpm2-totp error");
+                        sender.send(Message::Slots(Vec::new()))
+                            .expect("failed to send tpm2-totp slots");
+                        if let Some(changes) = changes {
+                            sender.send(Message::Changes(changes))
+                                .expect("failed to send tpm2-totp changes");
+                        }
                         thread::sleep(time::Duration::new(1, 0));
                     },
                 }
 
+                sender.send(Message::PcrState(client.tpm2_totp_pcr_state_matches().ok()))
+                    .expect("failed to send tpm2-totp PCR state");
+                sender.send(Message::ClockUnsafe(client.tpm_clock().map_or(false, |clock| ! clock.safe)))
+                    .expect("failed to send TPM clock state");
+
                 // Sleep until next TOTP window
                 let start = chrono::Utc::now().with_nanosecond(0).unwrap();
                 let end = if start.second() < 30 {
@@ -231,7 +866,10 @@ fn tpm<C: ContainerExt>(container: &C) {
                 } else {
                     start.with_second(0).unwrap() + chrono::Duration::minutes(1)
                 };
-                while ! refresh.swap(false, Ordering::Relaxed) {
+                while ! refresh_requested() {
+                    if paused() || stopped() {
+                        break;
+                    }
                     let current = chrono::Utc::now().with_nanosecond(0).unwrap();
                     let remaining = end.signed_duration_since(current).num_seconds();
                     sender.send(Message::Timeout(
@@ -240,19 +878,71 @@ fn tpm<C: ContainerExt>(container: &C) {
                     if remaining <= 0 {
                         break;
                     }
-                    thread::sleep(time::Duration::new(1, 0));
+                    // Do not sleep past the end of the window, to fetch the next code on time
+                    let sleep = remaining.min(poll_interval.into()) as u64;
+                    thread::sleep(time::Duration::new(sleep, 0));
                 }
             }
         });
     }
 
-    let label = gtk::Label::new(None);
+    // The polling thread exits on its own once stopped, releasing its DBus connection. It is not
+    // joined, as it may be sleeping or waiting on the daemon, which would hang the main thread.
+    list_box.connect_destroy(move |_| {
+        stop.store(true, Ordering::Relaxed);
+    });
+
+    let label = cascade! {
+        gtk::Label::new(None);
+        ..set_selectable(true);
+        ..style_context().add_class("monospace");
+    };
+    // The current code and the one of the previous window, as shown when not hidden
+    let code_text: Rc<RefCell<Option<String>>> = Rc::new(RefCell::new(None));
+    let previous_text = Rc::new(RefCell::new(String::new()));
+    // The code is shown grouped, but copied without the thin space so it can be pasted as is
+    {
+        let code_text = code_text.clone();
+        label.connect_copy_clipboard(move |label| {
+            label.stop_signal_emission("copy-clipboard");
+            if let Some(code) = &*code_text.borrow() {
+                let text: String = code.chars().filter(|c| c.is_ascii_digit()).collect();
+                gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD).set_text(&text);
+            }
+        });
+    }
+    // Hovering the code reveals it when hidden
+    let label_event_box = cascade! {
+        gtk::EventBox::new();
+        ..add_events(gtk::gdk::EventMask::ENTER_NOTIFY_MASK);
+        ..add(&label);
+    };
+    let previous_label = cascade! {
+        gtk::Label::new(None);
+        ..set_no_show_all(true);
+        ..set_visible(false);
+        ..style_context().add_class("dim-label");
+        ..style_context().add_class("monospace");
+    };
+    let reveal_button = cascade! {
+        gtk::Button::from_icon_name(Some("view-reveal-symbolic"), gtk::IconSize::Button);
+        ..set_tooltip_text(Some(&fl!("tpm2-totp-reveal")));
+        ..set_no_show_all(true);
+        ..set_valign(gtk::Align::Center);
+        ..set_visible(false);
+    };
     let progress_bar = cascade! {
         gtk::ProgressBar::new();
         ..set_no_show_all(true);
         ..set_valign(gtk::Align::Center);
         ..set_visible(false);
     };
+    // Authenticator apps commonly show a ring instead of a bar, which can be chosen instead
+    let countdown_fraction = Rc::new(Cell::new(0.0));
+    let countdown = countdown_ring(countdown_fraction.clone());
+    let use_countdown = Rc::new(Cell::new(
+        settings().map_or(false, |settings| settings.boolean("tpm2-totp-countdown-ring"))
+    ));
     let init_button = cascade! {
         gtk::Button::with_label(&fl!("tpm2-totp-init-button"));
         ..set_no_show_all(true);
@@ -268,50 +958,527 @@ fn tpm<C: ContainerExt>(container: &C) {
     let row = cascade! {
         libhandy::ActionRow::new();
         ..set_title(Some(&fl!("tpm2-totp")));
-        ..add(&label);
+        ..add(&previous_label);
+        ..add(&label_event_box);
+        ..add(&reveal_button);
         ..add(&progress_bar);
+        ..add(&countdown);
         ..add(&init_button);
         ..add(&reseal_button);
     };
     list_box.add(&row);
 
+    let pcr_state_label = gtk::Label::new(None);
+    let pcr_state_row = cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm2-totp-pcr-state")));
+        ..add(&pcr_state_label);
+        ..set_no_show_all(true);
+    };
+    list_box.add(&pcr_state_row);
+
+    let clock_unsafe_row = cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm-clock-unsafe")));
+        ..set_subtitle(Some(&fl!("tpm-clock-unsafe-warning")));
+        ..add(&gtk::Image::from_icon_name(Some("dialog-warning-symbolic"), gtk::IconSize::Button));
+        ..set_no_show_all(true);
+    };
+    list_box.add(&clock_unsafe_row);
+
+    {
+        let mapped = mapped.clone();
+        list_box.connect_map(move |_| mapped.store(true, Ordering::Relaxed));
+    }
+    list_box.connect_unmap(move |_| mapped.store(false, Ordering::Relaxed));
+
+    let auto_refresh_switch = cascade! {
+        gtk::Switch::new();
+        ..set_active(auto_refresh.load(Ordering::Relaxed));
+        ..set_valign(gtk::Align::Center);
+    };
+    auto_refresh_switch.connect_active_notify(move |switch| {
+        auto_refresh.store(switch.is_active(), Ordering::Relaxed);
+        if let Some(settings) = settings() {
+            if let Err(err) = settings.set_boolean("tpm2-totp-auto-refresh", switch.is_active()) {
+                eprintln!("failed to save automatic refresh setting: {}", err);
+            }
+        }
+    });
+    list_box.add(&cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm2-totp-auto-refresh")));
+        ..add(&auto_refresh_switch);
+        ..set_activatable_widget(Some(&auto_refresh_switch));
+    });
+
+    // Mirroring the code to a notification shows it outside of this window, so it is opt in
+    let notify = Rc::new(Cell::new(
+        settings().map_or(false, |settings| settings.boolean("tpm2-totp-notification"))
+    ));
+    let notify_switch = cascade! {
+        gtk::Switch::new();
+        ..set_active(notify.get());
+        ..set_valign(gtk::Align::Center);
+    };
+    {
+        let notify = notify.clone();
+        notify_switch.connect_active_notify(move |switch| {
+            notify.set(switch.is_active());
+            if ! switch.is_active() {
+                if let Some(app) = gio::Application::default() {
+                    app.withdraw_notification(CODE_NOTIFICATION_ID);
+                }
+            }
+            if let Some(settings) = settings() {
+                if let Err(err) = settings.set_boolean("tpm2-totp-notification", switch.is_active()) {
+                    eprintln!("failed to save notification setting: {}", err);
+                }
+            }
+        });
+    }
+    list_box.add(&cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm2-totp-notification")));
+        ..set_subtitle(Some(&fl!("tpm2-totp-notification-warning")));
+        ..add(&notify_switch);
+        ..set_activatable_widget(Some(&notify_switch));
+    });
+
+    let countdown_switch = cascade! {
+        gtk::Switch::new();
+        ..set_active(use_countdown.get());
+        ..set_valign(gtk::Align::Center);
+    };
+    {
+        let progress_bar = progress_bar.clone();
+        let countdown = countdown.clone();
+        let use_countdown = use_countdown.clone();
+        countdown_switch.connect_active_notify(move |switch| {
+            use_countdown.set(switch.is_active());
+            // Swap the indicators while a code is shown
+            if progress_bar.is_visible() || countdown.is_visible() {
+                progress_bar.set_visible(! switch.is_active());
+                countdown.set_visible(switch.is_active());
+            }
+            if let Some(settings) = settings() {
+                if let Err(err) = settings.set_boolean("tpm2-totp-countdown-ring", switch.is_active()) {
+                    eprintln!("failed to save countdown ring setting: {}", err);
+                }
+            }
+        });
+    }
+    list_box.add(&cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm2-totp-countdown-ring")));
+        ..add(&countdown_switch);
+        ..set_activatable_widget(Some(&countdown_switch));
+    });
+
+    // For privacy in shared spaces, dots can be shown in place of the code until it is revealed.
+    // The code keeps updating underneath.
+    let hide_code = Rc::new(Cell::new(
+        settings().map_or(false, |settings| settings.boolean("tpm2-totp-hide-code"))
+    ));
+    let revealed = Rc::new(Cell::new(false));
+    let update_code = {
+        let label = label.clone();
+        let previous_label = previous_label.clone();
+        let reveal_button = reveal_button.clone();
+        let code_text = code_text.clone();
+        let previous_text = previous_text.clone();
+        let hide_code = hide_code.clone();
+        let revealed = revealed.clone();
+        Rc::new(move || {
+            let masked = hide_code.get() && ! revealed.get();
+            if let Some(code) = &*code_text.borrow() {
+                label.set_text(&if masked { masked_code(code) } else { code.clone() });
+            }
+            let previous = previous_text.borrow();
+            previous_label.set_text(&if masked { masked_code(&previous) } else { previous.clone() });
+            reveal_button.set_visible(masked && code_text.borrow().is_some());
+        })
+    };
+    // Revealing lasts a few seconds, restarting whenever the code is revealed again
+    let reveal_count = Rc::new(Cell::new(0u32));
+    let reveal = {
+        let revealed = revealed.clone();
+        let update_code = update_code.clone();
+        Rc::new(move || {
+            revealed.set(true);
+            update_code();
+            let count = reveal_count.get().wrapping_add(1);
+            reveal_count.set(count);
+            let revealed = revealed.clone();
+            let update_code = update_code.clone();
+            let reveal_count = reveal_count.clone();
+            glib::timeout_add_seconds_local(CODE_REVEAL_SECONDS, move || {
+                if reveal_count.get() == count {
+                    revealed.set(false);
+                    update_code();
+                }
+                glib::Continue(false)
+            });
+        })
+    };
+    {
+        let reveal = reveal.clone();
+        reveal_button.connect_clicked(move |_| reveal());
+    }
+    {
+        let hide_code = hide_code.clone();
+        label_event_box.connect_enter_notify_event(move |_, _| {
+            if hide_code.get() {
+                reveal();
+            }
+            gtk::Inhibit(false)
+        });
+    }
+
+    let hide_code_switch = cascade! {
+        gtk::Switch::new();
+        ..set_active(hide_code.get());
+        ..set_valign(gtk::Align::Center);
+    };
+    {
+        let update_code = update_code.clone();
+        hide_code_switch.connect_active_notify(move |switch| {
+            hide_code.set(switch.is_active());
+            update_code();
+            if let Some(settings) = settings() {
+                if let Err(err) = settings.set_boolean("tpm2-totp-hide-code", switch.is_active()) {
+                    eprintln!("failed to save hide code setting: {}", err);
+                }
+            }
+        });
+    }
+    list_box.add(&cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm2-totp-hide-code")));
+        ..set_subtitle(Some(&fl!("tpm2-totp-hide-code-description")));
+        ..add(&hide_code_switch);
+        ..set_activatable_widget(Some(&hide_code_switch));
+    });
+
+    let label_accessible = label.accessible();
+    if let (Some(label_accessible), Some(row_accessible)) = (&label_accessible, row.accessible()) {
+        label_accessible.set_name(&fl!("tpm2-totp-code"));
+        label_accessible.add_relationship(atk::RelationType::LabelledBy, &row_accessible);
+    }
+
+    let advanced = cascade! {
+        libhandy::ExpanderRow::new();
+        ..set_title(Some(&fl!("tpm-advanced")));
+    };
+    let type_label = label_row(&advanced, &fl!("tpm-type"));
+    let authenticity_label = label_row(&advanced, &fl!("tpm-authenticity"));
+    let banks_label = label_row(&advanced, &fl!("tpm-banks"));
+    let uptime_label = label_row(&advanced, &fl!("tpm-uptime"));
+    let clock_label = label_row(&advanced, &fl!("tpm-clock"));
+    let reset_count_label = label_row(&advanced, &fl!("tpm-reset-count"));
+    let restart_count_label = label_row(&advanced, &fl!("tpm-restart-count"));
+    let diagnostics_button = cascade! {
+        gtk::Button::with_label(&fl!("tpm-diagnostics-copy"));
+        ..set_valign(gtk::Align::Center);
+    };
+    advanced.add(&cascade! {
+        libhandy::ActionRow::new();
+        ..set_title(Some(&fl!("tpm-diagnostics")));
+        ..add(&diagnostics_button);
+    });
+    list_box.add(&advanced);
+
+    diagnostics_button.connect_clicked(move |_| {
+        match DbusClient::new().and_then(|client| client.diagnostics()) {
+            Ok(diagnostics) => {
+                let clipboard = gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD);
+                clipboard.set_text(&diagnostics.to_report());
+            },
+            Err(err) => {
+                eprintln!("failed to collect diagnostics: {:?}", err);
+                error_dialog(&error_text(&err));
+            }
+        }
+    });
+
+    {
+        let client = DbusClient::new().unwrap(); // TODO: error handling
+        advanced.connect_expanded_notify(move |advanced| {
+            if ! advanced.is_expanded() {
+                return;
+            }
+
+            type_label.set_text(&match client.tpm_type() {
+                Ok(TpmType::Discrete) => fl!("tpm-type-discrete"),
+                Ok(TpmType::Firmware) => fl!("tpm-type-firmware"),
+                Ok(TpmType::Virtual) => fl!("tpm-type-virtual"),
+                Ok(TpmType::Unknown) => fl!("tpm-type-unknown"),
+                Err(err) => error_text(&err),
+            });
+
+            // Checked against the certificates of known TPM manufacturers
+            authenticity_label.set_text(&match client.tpm_ek_verified() {
+                Ok(true) => fl!("tpm-authenticity-verified"),
+                Ok(false) => fl!("tpm-authenticity-unverified"),
+                Err(err) => error_text(&err),
+            });
+
+            banks_label.set_text(&match client.tpm_active_banks() {
+                Ok(banks) => banks.iter().map(HashAlg::name).collect::<Vec<_>>().join(", "),
+                Err(err) => error_text(&err),
+            });
+
+            match client.tpm_clock() {
+                Ok(clock) => {
+                    uptime_label.set_text(&fl!("tpm-seconds", seconds = clock.time / 1000));
+                    clock_label.set_text(&fl!("tpm-seconds", seconds = clock.clock / 1000));
+                    reset_count_label.set_text(&clock.reset_count.to_string());
+                    restart_count_label.set_text(&clock.restart_count.to_string());
+                },
+                Err(err) => {
+                    eprintln!("failed to read TPM clock: {:?}", err);
+                    uptime_label.set_text(&error_text(&err));
+                    clock_label.set_text("");
+                    reset_count_label.set_text("");
+                    restart_count_label.set_text("");
+                }
+            }
+        });
+    }
+
     {
         let client = DbusClient::new().unwrap(); // TODO: error handling
         let refresh = refresh.clone();
         init_button.connect_clicked(move |button| {
             button.set_sensitive(false);
 
-            if let Some(password) = tpm_password_dialog(true) {
-                let result = client.tpm2_totp_init(&TotpPass(password));
-                refresh.swap(true, Ordering::Relaxed);
+            // Without measured boot, the secret would not notice firmware tampering
+            if let Ok(false) = client.measured_boot_active() {
+                let dialog = gtk::MessageDialog::new(
+                    None::<&gtk::Window>,
+                    gtk::DialogFlags::MODAL,
+                    gtk::MessageType::Warning,
+                    gtk::ButtonsType::OkCancel,
+                    &fl!("tpm2-totp-measured-boot-inactive")
+                );
+                let response = dialog.run();
+                dialog.hide();
+                if response != gtk::ResponseType::Ok {
+                    button.set_sensitive(true);
+                    return;
+                }
+            }
+
+            // Warn early instead of failing with NvSpaceFull after the password was entered
+            if let Ok(budget) = client.nv_budget() {
+                if budget.max_handles == 0 {
+                    let dialog = gtk::MessageDialog::new(
+                        None::<&gtk::Window>,
+                        gtk::DialogFlags::MODAL,
+                        gtk::MessageType::Warning,
+                        gtk::ButtonsType::OkCancel,
+                        &fl!("tpm2-totp-nv-space-low")
+                    );
+                    let response = dialog.run();
+                    dialog.hide();
+                    if response != gtk::ResponseType::Ok {
+                        button.set_sensitive(true);
+                        return;
+                    }
+                }
+            }
+
+            let mut pcrs = tpm2_totp_pcrs();
+            if let Some(password) = tpm_password_dialog(true, Some(&mut pcrs)) {
+                if let Some(settings) = settings() {
+                    if let Err(err) = settings.set_uint("tpm2-totp-pcrs", pcrs) {
+                        eprintln!("failed to save PCR selection: {}", err);
+                    }
+                }
+                let result = tpm_progress_dialog(&fl!("tpm2-totp-init-progress"), move || {
+                    DbusClient::new().and_then(|client| client.tpm2_totp_init(&TotpPass(password), pcrs))
+                });
+                refresh.fetch_add(1, Ordering::Relaxed);
                 match result {
-                    Ok(secret) => {
-                        let url = otpauth_url(&secret);
-
-                        //TODO: error handling and cleanup
-                        let qr = qrcode::QrCode::new(url).unwrap();
-                        let svg = qr.render::<qrcode::render::svg::Color>().build();
-                        let bytes = glib::Bytes::from(svg.as_bytes());
-                        let stream = gio::MemoryInputStream::from_bytes(&bytes);
-                        let pixbuf = gdk_pixbuf::Pixbuf::from_stream(
-                            &stream,
-                            None::<&gio::Cancellable>
-                        ).unwrap();
+                    Some(Ok(secret)) => {
+                        // The URL contains the secret, so it is wiped once the last widget using
+                        // it is destroyed
+                        let url = Rc::new(Zeroizing::new(secret.otpauth_url(otpauth::ISSUER, &otpauth::account())));
+
+                        // The secret is already sealed at this point, so it must be shown even
+                        // when the QR code cannot be rendered
+                        let mut ec_combo = None;
+                        let image: gtk::Widget = match qr_pixbuf(&url, qrcode::EcLevel::M) {
+                            Ok(pixbuf) => {
+                                let image = gtk::Image::from_pixbuf(Some(&pixbuf));
+
+                                // Higher error correction keeps printed codes scannable when damaged
+                                let combo = cascade! {
+                                    gtk::ComboBoxText::new();
+                                    ..append(Some("L"), &fl!("qr-ec-low"));
+                                    ..append(Some("M"), &fl!("qr-ec-medium"));
+                                    ..append(Some("Q"), &fl!("qr-ec-quartile"));
+                                    ..append(Some("H"), &fl!("qr-ec-high"));
+                                    ..set_active_id(Some("M"));
+                                    ..set_halign(gtk::Align::Center);
+                                    ..set_tooltip_text(Some(&fl!("qr-ec")));
+                                };
+                                {
+                                    let image = image.clone();
+                                    let url = url.clone();
+                                    combo.connect_changed(move |combo| {
+                                        let ec_level = match combo.active_id().as_deref() {
+                                            Some("L") => qrcode::EcLevel::L,
+                                            Some("Q") => qrcode::EcLevel::Q,
+                                            Some("H") => qrcode::EcLevel::H,
+                                            _ => qrcode::EcLevel::M,
+                                        };
+                                        match qr_pixbuf(&url, ec_level) {
+                                            Ok(pixbuf) => image.set_from_pixbuf(Some(&pixbuf)),
+                                            Err(err) => eprintln!("failed to render QR code: {}", err),
+                                        }
+                                    });
+                                }
+                                ec_combo = Some(combo);
+
+                                image.upcast()
+                            },
+                            Err(err) => {
+                                eprintln!("failed to render QR code: {}", err);
+                                cascade! {
+                                    gtk::Label::new(Some(&fl!("tpm2-totp-qr-failed", url = url.as_str())));
+                                    ..set_line_wrap(true);
+                                    ..set_line_wrap_mode(gtk::pango::WrapMode::Char);
+                                    ..set_max_width_chars(40);
+                                    ..set_selectable(true);
+                                }.upcast()
+                            }
+                        };
+
+                        // For authenticator apps that cannot scan the QR code
+                        let manual_label = cascade! {
+                            gtk::Label::new(Some(&fl!("tpm2-totp-manual-key")));
+                            ..set_line_wrap(true);
+                            ..set_max_width_chars(40);
+                        };
+                        let key_label = {
+                            let key = Zeroizing::new(secret.as_base32_groups());
+                            cascade! {
+                                gtk::Label::new(Some(&key));
+                                ..set_selectable(true);
+                                ..style_context().add_class("monospace");
+                            }
+                        };
 
                         //TODO: improve dialog
-                        let image = gtk::Image::from_pixbuf(Some(&pixbuf));
-                        cascade! {
+                        let checksum_label = gtk::Label::new(Some(
+                            &fl!("tpm2-totp-checksum", checksum = secret.checksum())
+                        ));
+                        let copied_label = cascade! {
+                            gtk::Label::new(Some(&fl!("tpm2-totp-copied", seconds = CLIPBOARD_CLEAR_SECONDS)));
+                            ..set_line_wrap(true);
+                            ..set_max_width_chars(40);
+                            ..set_no_show_all(true);
+                            ..set_visible(false);
+                        };
+                        let copy_button = cascade! {
+                            gtk::Button::with_label(&fl!("tpm2-totp-copy-link"));
+                            ..set_halign(gtk::Align::Center);
+                        };
+                        {
+                            let copied_label = copied_label.clone();
+                            copy_button.connect_clicked(move |_| {
+                                let clipboard = gtk::Clipboard::get(&gtk::gdk::SELECTION_CLIPBOARD);
+                                clipboard.set_text(&url);
+                                copied_label.set_visible(true);
+
+                                // Clear the clipboard, unless something else was copied since
+                                let url = url.clone();
+                                glib::timeout_add_seconds_local(CLIPBOARD_CLEAR_SECONDS, move || {
+                                    if clipboard.wait_for_text().map_or(false, |text| text.as_str() == url.as_str()) {
+                                        clipboard.set_text("");
+                                    }
+                                    glib::Continue(false)
+                                });
+                            });
+                        }
+                        // Without a QR code there is no error correction to choose
+                        let ec_combo_widget: gtk::Widget = match ec_combo {
+                            Some(combo) => combo.upcast(),
+                            None => gtk::Box::new(gtk::Orientation::Vertical, 0).upcast(),
+                        };
+                        let timeout_label = cascade! {
+                            gtk::Label::new(Some(&fl!("tpm2-totp-secret-timeout", seconds = SECRET_DIALOG_TIMEOUT_SECONDS)));
+                            ..set_line_wrap(true);
+                            ..set_max_width_chars(40);
+                        };
+                        let dialog = cascade! {
                             gtk::Dialog::new();
                             ..add_button(&fl!("ok"), gtk::ResponseType::Ok);
+                            ..add_events(gtk::gdk::EventMask::POINTER_MOTION_MASK);
                             ..content_area().add(&image);
+                            ..content_area().add(&manual_label);
+                            ..content_area().add(&key_label);
+                            ..content_area().add(&checksum_label);
+                            ..content_area().add(&ec_combo_widget);
+                            ..content_area().add(&copy_button);
+                            ..content_area().add(&copied_label);
+                            ..content_area().add(&timeout_label);
                             ..show_all();
-                            ..run();
-                            ..hide();
                         };
+
+                        // Close the dialog if left unattended, restarting the timeout whenever the
+                        // user interacts with it
+                        let last_activity = Rc::new(Cell::new(time::Instant::now()));
+                        {
+                            let last_activity = last_activity.clone();
+                            dialog.connect_event(move |_, event| {
+                                match event.event_type() {
+                                    gtk::gdk::EventType::MotionNotify
+                                    | gtk::gdk::EventType::ButtonPress
+                                    | gtk::gdk::EventType::KeyPress
+                                    | gtk::gdk::EventType::Scroll => {
+                                        last_activity.set(time::Instant::now());
+                                    },
+                                    _ => (),
+                                }
+                                gtk::Inhibit(false)
+                            });
+                        }
+                        let closed = Rc::new(Cell::new(false));
+                        {
+                            let closed = closed.clone();
+                            let dialog = dialog.clone();
+                            glib::timeout_add_seconds_local(1, move || {
+                                if closed.get() {
+                                    return glib::Continue(false);
+                                }
+                                if last_activity.get().elapsed() >= time::Duration::from_secs(SECRET_DIALOG_TIMEOUT_SECONDS) {
+                                    dialog.response(gtk::ResponseType::DeleteEvent);
+                                    return glib::Continue(false);
+                                }
+                                glib::Continue(true)
+                            });
+                        }
+                        dialog.run();
+                        closed.set(true);
+                        dialog.hide();
+                        // Release the QR pixbuf and the closures holding the URL right away,
+                        // instead of whenever the dialog would be finalized
+                        unsafe { dialog.destroy(); }
+                        drop(url);
+                        drop(secret);
+
+                        if ! tpm_verify_dialog(&client) {
+                            error_dialog(&fl!("tpm2-totp-verify-cancelled"));
+                        }
                     },
-                    Err(err) => {
-                        //TODO: send to GUI
-                        println!("failed to initialize: {:?}", err);
+                    Some(Err(err)) => {
+                        eprintln!("failed to initialize: {:?}", err);
+                        error_dialog(&error_text(&err));
+                    },
+                    None => {
+                        error_dialog(&fl!("tpm2-totp-init-cancelled"));
                     }
                 }
             }
@@ -321,20 +1488,35 @@ fn tpm<C: ContainerExt>(container: &C) {
     }
 
     {
-        let client = DbusClient::new().unwrap(); // TODO: error handling
         let refresh = refresh.clone();
         reseal_button.connect_clicked(move |button| {
             button.set_sensitive(false);
 
-            if let Some(password) = tpm_password_dialog(false) {
-                let result = client.tpm2_totp_reseal(&TotpPass(password));
-                refresh.swap(true, Ordering::Relaxed);
-                match result {
-                    Ok(()) => (),
-                    Err(err) => {
-                        //TODO: send to GUI
-                        println!("failed to reseal: {:?}", err);
+            if let Some(password) = tpm_password_dialog(false, None) {
+                let pcrs = tpm2_totp_pcrs();
+                loop {
+                    let password = password.clone();
+                    let result = tpm_progress_dialog(&fl!("tpm2-totp-reseal-progress"), move || {
+                        DbusClient::new().and_then(|client| client.tpm2_totp_reseal(&TotpPass(password), pcrs))
+                    });
+                    refresh.fetch_add(1, Ordering::Relaxed);
+                    match result {
+                        Some(Ok(())) => (),
+                        // Once other TPM applications are closed, the same reseal can succeed
+                        Some(Err(DbusError::Totp(TotpError::TpmBusy))) => {
+                            if retry_dialog(&fl!("tpm-busy")) {
+                                continue;
+                            }
+                        },
+                        Some(Err(err)) => {
+                            eprintln!("failed to reseal: {:?}", err);
+                            error_dialog(&error_text(&err));
+                        },
+                        None => {
+                            error_dialog(&fl!("tpm2-totp-reseal-cancelled"));
+                        }
                     }
+                    break;
                 }
             }
 
@@ -342,16 +1524,52 @@ fn tpm<C: ContainerExt>(container: &C) {
         });
     }
 
+    // Rows of the other secrets, replaced on every refresh
+    let mut slot_rows: Vec<libhandy::ActionRow> = Vec::new();
+
+    let show_previous = settings().map_or(true, |settings| settings.boolean("tpm2-totp-show-previous"));
+    let mut last_code = None;
     receiver.attach(None, move |message| {
         match message {
             Message::Code(code) => {
-                label.set_text(&format!("{:06}", code.0));
-                progress_bar.set_visible(true);
+                // Keep showing the code of the previous window, in case it was being typed in
+                match last_code {
+                    Some(last) if show_previous && last != code.0 => {
+                        *previous_text.borrow_mut() = TotpCode(last).formatted_grouped();
+                        previous_label.set_visible(true);
+                    },
+                    _ => previous_label.set_visible(false),
+                }
+                last_code = Some(code.0);
+
+                // Sending with the same ID replaces the notification of the previous window
+                if notify.get() {
+                    if let Some(app) = gio::Application::default() {
+                        let notification = gio::Notification::new(&fl!("tpm2-totp-code"));
+                        notification.set_body(Some(&code.formatted_grouped()));
+                        app.send_notification(Some(CODE_NOTIFICATION_ID), &notification);
+                    }
+                }
+
+                *code_text.borrow_mut() = Some(code.formatted_grouped());
+                update_code();
+                row.set_subtitle(None);
+                if let Some(accessible) = &label_accessible {
+                    accessible.set_description("");
+                }
+                progress_bar.set_visible(! use_countdown.get());
+                countdown.set_visible(use_countdown.get());
                 init_button.set_visible(false);
                 reseal_button.set_visible(false);
             },
             Message::Error(error) => {
+                last_code = None;
+                *code_text.borrow_mut() = None;
+                reveal_button.set_visible(false);
+                row.set_subtitle(None);
+                previous_label.set_visible(false);
                 progress_bar.set_visible(false);
+                countdown.set_visible(false);
                 init_button.set_visible(false);
                 reseal_button.set_visible(false);
                 match error {
@@ -364,12 +1582,77 @@ fn tpm<C: ContainerExt>(container: &C) {
                         reseal_button.set_visible(true);
                     },
                     _ => {
-                        label.set_text(&format!("{}", error));
+                        label.set_text(&error_text(&error));
                     }
                 }
             },
+            Message::Slots(slots) => {
+                for slot_row in slot_rows.drain(..) {
+                    list_box.remove(&slot_row);
+                }
+                for (i, slot) in slots.into_iter().enumerate() {
+                    let pcrs: Vec<String> = (0..32)
+                        .filter(|pcr| slot.pcrs & (1 << pcr) != 0)
+                        .map(|pcr: u32| pcr.to_string())
+                        .collect();
+                    let text = match slot.code {
+                        Ok(code) => code.formatted_grouped(),
+                        Err(err) => error_text(&DbusError::Totp(err)),
+                    };
+                    let slot_row = cascade! {
+                        libhandy::ActionRow::new();
+                        ..set_title(Some(&fl!("tpm2-totp-slot", index = format!("0x{:08X}", slot.nv_index))));
+                        ..set_subtitle(Some(&fl!("tpm2-totp-slot-pcrs", pcrs = pcrs.join(", "))));
+                        ..add(&cascade! {
+                            gtk::Label::new(Some(&text));
+                            ..set_selectable(true);
+                            ..style_context().add_class("monospace");
+                        });
+                        ..show_all();
+                    };
+                    list_box.insert(&slot_row, row.index() + 1 + i as i32);
+                    slot_rows.push(slot_row);
+                }
+            },
+            Message::Changes(changes) => if !changes.is_empty() {
+                row.set_subtitle(Some(&fl!("tpm2-totp-changes", changes = changes.join(", "))));
+            },
+            Message::ResealBlocked(blocked) => {
+                reseal_button.set_sensitive(blocked.is_none());
+                reseal_button.set_tooltip_text(blocked.as_deref());
+            },
+            Message::PcrState(matches) => {
+                pcr_state_row.set_visible(matches.is_some());
+                if let Some(matches) = matches {
+                    pcr_state_label.set_text(&if matches {
+                        fl!("tpm2-totp-pcr-state-matches")
+                    } else {
+                        fl!("tpm2-totp-pcr-state-changed")
+                    });
+                    pcr_state_row.show_all();
+                }
+            },
+            Message::ClockUnsafe(clock_unsafe) => {
+                clock_unsafe_row.set_visible(clock_unsafe);
+                if clock_unsafe {
+                    clock_unsafe_row.show_all();
+                }
+            },
             Message::Timeout(timeout) => {
                 progress_bar.set_fraction(timeout);
+                countdown_fraction.set(timeout);
+                countdown.queue_draw();
+                if timeout * 30.0 >= PREVIOUS_CODE_SECONDS {
+                    previous_label.set_visible(false);
+                }
+
+                // Let assistive technologies announce that the code is about to change
+                let remaining = ((1.0 - timeout) * 30.0).round() as u32;
+                if let Some(accessible) = &label_accessible {
+                    if remaining <= 5 {
+                        accessible.set_description(&fl!("tpm2-totp-expires", seconds = remaining));
+                    }
+                }
             },
         }
         glib::Continue(true)
@@ -396,7 +1679,19 @@ impl PopSecWidget {
         container.add(&scrolled_window);
 
         secure_boot(&vbox);
-        tpm(&vbox);
+
+        // Without the daemon, the TPM section could only show errors
+        match DbusClient::new().and_then(|client| client.ping()) {
+            Ok(_) => tpm(&vbox),
+            Err(err) => {
+                eprintln!("failed to reach popsec-daemon: {:?}", err);
+                let list_box = settings_list_box(&vbox, &fl!("tpm"));
+                list_box.add(&cascade! {
+                    libhandy::ActionRow::new();
+                    ..set_title(Some(&error_text(&err)));
+                });
+            }
+        }
 
         Self
     }
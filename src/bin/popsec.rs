@@ -0,0 +1,39 @@
+use popsec::{
+    dbus::Client,
+    status::Health,
+};
+use std::{
+    env,
+    process,
+};
+
+// Report whether the TOTP secret still unseals, in the format of a Nagios plugin
+fn health() -> i32 {
+    let result = Client::new().and_then(|client| client.health());
+    let health = match result {
+        Ok(ok) => ok,
+        Err(err) => {
+            println!("UNKNOWN - failed to query popsec-daemon: {}", err);
+            return Health::Error.exit_code();
+        }
+    };
+    let status = match health.exit_code() {
+        0 => "OK",
+        1 => "WARNING",
+        2 => "CRITICAL",
+        _ => "UNKNOWN",
+    };
+    println!("{} - tpm2-totp {}", status, health.as_str());
+    health.exit_code()
+}
+
+fn main() {
+    let code = match env::args().nth(1).as_deref() {
+        Some("health") => health(),
+        _ => {
+            eprintln!("usage: popsec health");
+            Health::Error.exit_code()
+        }
+    };
+    process::exit(code);
+}
@@ -1,15 +1,51 @@
-use dbus::{ffidisp::Connection, Message};
+use dbus::{ffidisp::Connection, message::MessageType, Message};
+use std::{
+    cell::Cell,
+    time::{Duration, Instant},
+};
 use thiserror::Error;
 
-use crate::tpm2_totp::*;
+use crate::{
+    status::{Health, SecurityStatus},
+    tpm2_totp::*,
+};
 
 pub const DBUS_DEST: &str = "com.system76.PopSec";
 pub const DBUS_IFACE: &str = DBUS_DEST;
 pub const DBUS_PATH: &str = "/com/system76/PopSec";
 
+/// Version of the DBus interface, incremented whenever a method changes in a way that older clients
+/// or daemons cannot handle. Returned by `METHOD_PING`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+pub const METHOD_DIAGNOSTICS: &str = "Diagnostics";
+pub const METHOD_HEALTH: &str = "Health";
+pub const METHOD_LAST_ERROR: &str = "LastError";
+pub const METHOD_MEASURED_BOOT_ACTIVE: &str = "MeasuredBootActive";
+pub const METHOD_NV_BUDGET: &str = "NvBudget";
+pub const METHOD_PING: &str = "Ping";
+pub const METHOD_SECURE_BOOT_ENROLL_DEFAULT_KEYS: &str = "SecureBootEnrollDefaultKeys";
+pub const METHOD_SECURE_BOOT_KEYS_CHANGED: &str = "SecureBootKeysChanged";
+pub const METHOD_SECURITY_STATUS: &str = "SecurityStatus";
+pub const METHOD_SET_MAINTENANCE: &str = "SetMaintenance";
+pub const METHOD_TPM2_TOTP_ATTEST: &str = "Tpm2TotpAttest";
+pub const METHOD_TPM2_TOTP_CHANGES: &str = "Tpm2TotpChanges";
+pub const METHOD_TPM2_TOTP_HAS_RECOVERY_PASSWORD: &str = "Tpm2TotpHasRecoveryPassword";
 pub const METHOD_TPM2_TOTP_INIT: &str = "Tpm2TotpInit";
+pub const METHOD_TPM2_TOTP_NV_LOCKED: &str = "Tpm2TotpNvLocked";
+pub const METHOD_TPM2_TOTP_OTPAUTH: &str = "Tpm2TotpOtpauth";
+pub const METHOD_TPM2_TOTP_PCR_STATE_MATCHES: &str = "Tpm2TotpPcrStateMatches";
 pub const METHOD_TPM2_TOTP_RESEAL: &str = "Tpm2TotpReseal";
 pub const METHOD_TPM2_TOTP_SHOW: &str = "Tpm2TotpShow";
+pub const METHOD_TPM2_TOTP_SHOW_ALL: &str = "Tpm2TotpShowAll";
+pub const METHOD_TPM_ACTIVE_BANKS: &str = "TpmActiveBanks";
+pub const METHOD_TPM_CLOCK: &str = "TpmClock";
+pub const METHOD_TPM_EK_VERIFIED: &str = "TpmEkVerified";
+pub const METHOD_TPM_TYPE: &str = "TpmType";
+
+/// Emitted with the phase, step, and number of steps as each phase of `METHOD_TPM2_TOTP_INIT` and
+/// `METHOD_TPM2_TOTP_RESEAL` starts.
+pub const SIGNAL_TPM2_TOTP_PROGRESS: &str = "Tpm2TotpProgress";
 
 /// An error that may occur when interacting with the popsec daemon.
 #[derive(Debug, Error)]
@@ -28,37 +64,74 @@ pub enum Error {
     NewMethodCall(&'static str, Box<str>),
     #[error("TOTP error: {}", _0)]
     Totp(TotpError),
+    /// The daemon speaks a different protocol version than this client, given as (daemon,
+    /// client). Daemons from before versioning report version 0.
+    #[error("popsec-daemon protocol version {} does not match client version {}", _0, _1)]
+    VersionMismatch(u32, u32),
+}
+
+// Name of the DBus error that a `TotpError` is sent as
+fn error_name(err: &TotpError) -> &'static str {
+    match err {
+        TotpError::NoPasswordProvided => {
+            "com.system76.PopSec.Error.NoPasswordProvided"
+        },
+        TotpError::SecretHasNoPassword => {
+            "com.system76.PopSec.Error.SecretHasNoPassword"
+        },
+        TotpError::SecretAlreadyExists => {
+            "com.system76.PopSec.Error.SecretAlreadyExists"
+        },
+        TotpError::SecretNotFound => {
+            "com.system76.PopSec.Error.SecretNotFound"
+        },
+        TotpError::SystemStateChanged => {
+            "com.system76.PopSec.Error.SystemStateChanged"
+        },
+        TotpError::WrongPassword => {
+            "com.system76.PopSec.Error.WrongPassword"
+        },
+        TotpError::Lockout => {
+            "com.system76.PopSec.Error.Lockout"
+        },
+        TotpError::InvalidPassword => {
+            "com.system76.PopSec.Error.InvalidPassword"
+        },
+        TotpError::NvSpaceFull => {
+            "com.system76.PopSec.Error.NvSpaceFull"
+        },
+        TotpError::NvLocked => {
+            "com.system76.PopSec.Error.NvLocked"
+        },
+        TotpError::NotStarted => {
+            "com.system76.PopSec.Error.NotStarted"
+        },
+        TotpError::IndexInUse => {
+            "com.system76.PopSec.Error.IndexInUse"
+        },
+        TotpError::CorruptBlob => {
+            "com.system76.PopSec.Error.CorruptBlob"
+        },
+        TotpError::Maintenance => {
+            "com.system76.PopSec.Error.Maintenance"
+        },
+        TotpError::TpmBusy => {
+            "com.system76.PopSec.Error.TpmBusy"
+        },
+        TotpError::InvalidSecret => {
+            "com.system76.PopSec.Error.InvalidSecret"
+        },
+        // The message of unknown errors contains the RC as `rc=0x...`, which is parsed back out
+        // by the client
+        TotpError::Unknown(_) | TotpError::Other(_) => {
+            "com.system76.PopSec.Error.Other"
+        },
+    }
 }
 
 impl From<TotpError> for dbus::Error {
     fn from(err: TotpError) -> dbus::Error {
-        let name = match err {
-            TotpError::NoPasswordProvided => {
-                "com.system76.PopSec.Error.NoPasswordProvided"
-            },
-            TotpError::SecretHasNoPassword => {
-                "com.system76.PopSec.Error.SecretHasNoPassword"
-            },
-            TotpError::SecretAlreadyExists => {
-                "com.system76.PopSec.Error.SecretAlreadyExists"
-            },
-            TotpError::SecretNotFound => {
-                "com.system76.PopSec.Error.SecretNotFound"
-            },
-            TotpError::SystemStateChanged => {
-                "com.system76.PopSec.Error.SystemStateChanged"
-            },
-            TotpError::WrongPassword => {
-                "com.system76.PopSec.Error.WrongPassword"
-            },
-            TotpError::Lockout => {
-                "com.system76.PopSec.Error.Lockout"
-            },
-            TotpError::Other(_) => {
-                "com.system76.PopSec.Error.Other"
-            },
-        };
-        dbus::Error::new_custom(name, &err.to_string())
+        dbus::Error::new_custom(error_name(&err), &err.to_string())
     }
 }
 
@@ -97,24 +170,113 @@ impl TryFrom<dbus::Error> for TotpError {
             "com.system76.PopSec.Error.Lockout" => Ok(
                 TotpError::Lockout,
             ),
-            "com.system76.PopSec.Error.Other" => Ok(
-                TotpError::Other(
-                    dbus.message().map_or(String::new(), |x| x.to_string())
-                ),
+            "com.system76.PopSec.Error.InvalidPassword" => Ok(
+                TotpError::InvalidPassword,
             ),
+            "com.system76.PopSec.Error.NvSpaceFull" => Ok(
+                TotpError::NvSpaceFull,
+            ),
+            "com.system76.PopSec.Error.NvLocked" => Ok(
+                TotpError::NvLocked,
+            ),
+            "com.system76.PopSec.Error.NotStarted" => Ok(
+                TotpError::NotStarted,
+            ),
+            "com.system76.PopSec.Error.IndexInUse" => Ok(
+                TotpError::IndexInUse,
+            ),
+            "com.system76.PopSec.Error.CorruptBlob" => Ok(
+                TotpError::CorruptBlob,
+            ),
+            "com.system76.PopSec.Error.Maintenance" => Ok(
+                TotpError::Maintenance,
+            ),
+            "com.system76.PopSec.Error.TpmBusy" => Ok(
+                TotpError::TpmBusy,
+            ),
+            "com.system76.PopSec.Error.InvalidSecret" => Ok(
+                TotpError::InvalidSecret,
+            ),
+            "com.system76.PopSec.Error.Other" => {
+                let message = dbus.message().unwrap_or("");
+                Ok(match parse_rc(message) {
+                    Some(rc) => TotpError::Unknown(rc),
+                    None => TotpError::Other(message.to_string()),
+                })
+            },
             _ => Err(dbus),
         }
     }
 }
 
+// Extracts the RC from a message containing `rc=0x...`
+fn parse_rc(message: &str) -> Option<u32> {
+    let start = message.find("rc=0x")? + "rc=0x".len();
+    let digits = &message[start..];
+    let end = digits.find(|c: char| ! c.is_ascii_hexdigit()).unwrap_or(digits.len());
+    u32::from_str_radix(&digits[..end], 16).ok()
+}
+
+/// Errors of single secrets in `METHOD_TPM2_TOTP_SHOW_ALL` are sent as the name and message of the
+/// DBus error the method would otherwise fail with, with an empty name when there was no error.
+pub fn slot_error_name(err: TotpError) -> (String, String) {
+    let dbus = dbus::Error::from(err);
+    (
+        dbus.name().unwrap_or("").to_string(),
+        dbus.message().unwrap_or("").to_string(),
+    )
+}
+
+/// The last error is sent by `METHOD_LAST_ERROR` as the name and message of the DBus error it
+/// failed a method call with, and the TPM RC of unknown TPM errors, otherwise 0.
+pub fn error_details(err: &TotpError) -> (String, String, u32) {
+    (error_name(err).to_string(), err.to_string(), err.rc().unwrap_or(0))
+}
+
+fn slot_error(name: &str, message: &str) -> TotpError {
+    TotpError::try_from(dbus::Error::new_custom(name, message))
+        .unwrap_or_else(|_| TotpError::Other(message.to_string()))
+}
+
+/// Durations are sent as milliseconds, with 0 for operations that were not timed.
+pub fn duration_millis(duration: Option<Duration>) -> u64 {
+    duration.map_or(0, |duration| (duration.as_millis() as u64).max(1))
+}
+
+fn millis_duration(millis: u64) -> Option<Duration> {
+    if millis == 0 {
+        None
+    } else {
+        Some(Duration::from_millis(millis))
+    }
+}
+
 /// DBus client connection for interacting with the system76-firmware daemon.
-pub struct Client(Connection);
+pub struct Client {
+    connection: Connection,
+    // Code returned by `tpm2_totp_show_cached`, and the end of its window
+    cached_code: Cell<Option<(Instant, u64)>>,
+}
 
 impl Client {
     pub fn new() -> Result<Self, Error> {
         Connection::new_system()
             .map_err(Error::Connection)
-            .map(Self)
+            .map(Self::from_connection)
+    }
+
+    /// Connect to a daemon serving on the session bus with `--session`, for testing.
+    pub fn new_session() -> Result<Self, Error> {
+        Connection::new_session()
+            .map_err(Error::Connection)
+            .map(Self::from_connection)
+    }
+
+    fn from_connection(connection: Connection) -> Self {
+        Self {
+            connection,
+            cached_code: Cell::new(None),
+        }
     }
 
     /// Convenience method for calling a DBus method.
@@ -128,7 +290,7 @@ impl Client {
 
         m = append_args(m);
 
-        self.0
+        self.connection
             .send_with_reply_and_block(m, -1)
             .map_err(|why| match TotpError::try_from(why) {
                 Ok(ok) => Error::Totp(ok),
@@ -136,15 +298,180 @@ impl Client {
             })
     }
 
-    pub fn tpm2_totp_init(&self, password: &TotpPass) -> Result<TotpSecret, Error> {
-        self.call_method(METHOD_TPM2_TOTP_INIT, |m| m.append1(&password.0))?
+    pub fn diagnostics(&self) -> Result<Diagnostics, Error> {
+        self.call_method(METHOD_DIAGNOSTICS, |m| m)?
+            .read5::<
+                (String, u64),
+                (bool, u32, u32),
+                (u64, u64, u32, u32, bool),
+                (u32, u32, u32, u32, bool),
+                (u64, u64, u64),
+            >()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_DIAGNOSTICS, why))
+            .map(|(
+                (manufacturer, firmware_version),
+                (enrolled, pcrs, banks),
+                (time, clock, reset_count, restart_count, safe),
+                (lockout_counter, max_auth_fail, lockout_interval, lockout_recovery, in_lockout),
+                (init, reseal, show),
+            )| Diagnostics {
+                manufacturer,
+                firmware_version,
+                enrolled,
+                pcrs,
+                banks,
+                clock: TpmClock {
+                    time,
+                    clock,
+                    reset_count,
+                    restart_count,
+                    safe,
+                },
+                lockout_counter,
+                max_auth_fail,
+                lockout_interval,
+                lockout_recovery,
+                in_lockout,
+                timings: Timings {
+                    init: millis_duration(init),
+                    reseal: millis_duration(reseal),
+                    show: millis_duration(show),
+                },
+            })
+    }
+
+    pub fn health(&self) -> Result<Health, Error> {
+        self.call_method(METHOD_HEALTH, |m| m)?
+            .read1::<String>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_HEALTH, why))
+            .map(|health| health.parse().unwrap_or(Health::Error))
+    }
+
+    /// The error the daemon last failed a TPM method call with, for front ends that missed it.
+    pub fn last_error(&self) -> Result<Option<TotpError>, Error> {
+        self.call_method(METHOD_LAST_ERROR, |m| m)?
+            .read3::<String, String, u32>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_LAST_ERROR, why))
+            .map(|(name, message, rc)| if name.is_empty() {
+                None
+            } else if rc != 0 {
+                Some(TotpError::Unknown(rc))
+            } else {
+                Some(slot_error(&name, &message))
+            })
+    }
+
+    pub fn measured_boot_active(&self) -> Result<bool, Error> {
+        self.call_method(METHOD_MEASURED_BOOT_ACTIVE, |m| m)?
+            .read1::<bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_MEASURED_BOOT_ACTIVE, why))
+    }
+
+    pub fn nv_budget(&self) -> Result<NvBudget, Error> {
+        self.call_method(METHOD_NV_BUDGET, |m| m)?
+            .read2::<u32, u32>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_NV_BUDGET, why))
+            .map(|(available_bytes, max_handles)| NvBudget {
+                available_bytes,
+                max_handles,
+            })
+    }
+
+    /// Check that the daemon is running and speaks `PROTOCOL_VERSION`, returning its version.
+    pub fn ping(&self) -> Result<String, Error> {
+        let message = match self.call_method(METHOD_PING, |m| m) {
+            Ok(ok) => ok,
+            Err(Error::Call(_, err)) if err.name() == Some("org.freedesktop.DBus.Error.UnknownMethod") => {
+                return Err(Error::VersionMismatch(0, PROTOCOL_VERSION));
+            },
+            Err(err) => return Err(err),
+        };
+        let (version, protocol) = message.read2::<String, u32>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_PING, why))?;
+        if protocol != PROTOCOL_VERSION {
+            return Err(Error::VersionMismatch(protocol, PROTOCOL_VERSION));
+        }
+        Ok(version)
+    }
+
+    pub fn secure_boot_enroll_default_keys(&self) -> Result<(), Error> {
+        self.call_method(METHOD_SECURE_BOOT_ENROLL_DEFAULT_KEYS, |m| m)?;
+        Ok(())
+    }
+
+    /// Whether the Secure Boot keys changed since the TPM2 TOTP secret was set up. False when
+    /// there is no baseline from setting it up.
+    pub fn secure_boot_keys_changed(&self) -> Result<bool, Error> {
+        self.call_method(METHOD_SECURE_BOOT_KEYS_CHANGED, |m| m)?
+            .read1::<bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_SECURE_BOOT_KEYS_CHANGED, why))
+    }
+
+    pub fn security_status(&self) -> Result<SecurityStatus, Error> {
+        self.call_method(METHOD_SECURITY_STATUS, |m| m)?
+            .read4::<bool, bool, bool, bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_SECURITY_STATUS, why))
+            .map(|(secure_boot, setup_mode, tpm_present, enrolled)| SecurityStatus {
+                secure_boot,
+                setup_mode,
+                tpm_present,
+                enrolled,
+            })
+    }
+
+    /// While maintenance is enabled, such as during a firmware update, `tpm2_totp_show` returns
+    /// `TotpError::Maintenance` instead of prompting to reseal.
+    pub fn set_maintenance(&self, enabled: bool) -> Result<(), Error> {
+        self.call_method(METHOD_SET_MAINTENANCE, |m| m.append1(enabled))?;
+        Ok(())
+    }
+
+    pub fn tpm2_totp_attest(&self) -> Result<Vec<u8>, Error> {
+        self.call_method(METHOD_TPM2_TOTP_ATTEST, |m| m)?
+            .read1::<Vec<u8>>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_ATTEST, why))
+    }
+
+    pub fn tpm2_totp_changes(&self) -> Result<Vec<String>, Error> {
+        self.call_method(METHOD_TPM2_TOTP_CHANGES, |m| m)?
+            .read1::<Vec<String>>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_CHANGES, why))
+    }
+
+    pub fn tpm2_totp_has_recovery_password(&self) -> Result<bool, Error> {
+        self.call_method(METHOD_TPM2_TOTP_HAS_RECOVERY_PASSWORD, |m| m)?
+            .read1::<bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_HAS_RECOVERY_PASSWORD, why))
+    }
+
+    pub fn tpm2_totp_init(&self, password: &TotpPass, pcrs: u32) -> Result<TotpSecret, Error> {
+        self.call_method(METHOD_TPM2_TOTP_INIT, |m| m.append2(&password.0, pcrs))?
             .read1::<Vec<u8>>()
             .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_INIT, why))
-            .map(TotpSecret)
+            .and_then(|secret| TotpSecret::new(secret).map_err(Error::Totp))
+    }
+
+    pub fn tpm2_totp_nv_locked(&self) -> Result<bool, Error> {
+        self.call_method(METHOD_TPM2_TOTP_NV_LOCKED, |m| m)?
+            .read1::<bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_NV_LOCKED, why))
+    }
+
+    pub fn tpm2_totp_otpauth(&self, password: &TotpPass) -> Result<String, Error> {
+        self.call_method(METHOD_TPM2_TOTP_OTPAUTH, |m| m.append1(&password.0))?
+            .read1::<String>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_OTPAUTH, why))
+    }
+
+    /// Whether the PCRs still have the values the secret was sealed under.
+    pub fn tpm2_totp_pcr_state_matches(&self) -> Result<bool, Error> {
+        self.call_method(METHOD_TPM2_TOTP_PCR_STATE_MATCHES, |m| m)?
+            .read1::<bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_PCR_STATE_MATCHES, why))
     }
 
-    pub fn tpm2_totp_reseal(&self, password: &TotpPass) -> Result<(), Error> {
-        self.call_method(METHOD_TPM2_TOTP_RESEAL, |m| m.append1(&password.0))?;
+    pub fn tpm2_totp_reseal(&self, password: &TotpPass, pcrs: u32) -> Result<(), Error> {
+        self.call_method(METHOD_TPM2_TOTP_RESEAL, |m| m.append2(&password.0, pcrs))?;
         Ok(())
     }
 
@@ -154,4 +481,115 @@ impl Client {
             .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_SHOW, why))
             .map(TotpCode)
     }
+
+    /// The codes of every NV index holding a secret, in one call.
+    pub fn tpm2_totp_show_all(&self) -> Result<Vec<SlotCode>, Error> {
+        self.call_method(METHOD_TPM2_TOTP_SHOW_ALL, |m| m)?
+            .read1::<Vec<(u32, u32, u64, String, String)>>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM2_TOTP_SHOW_ALL, why))
+            .map(|slots| slots.into_iter().map(|(nv_index, pcrs, code, error_name, error_message)| SlotCode {
+                nv_index,
+                pcrs,
+                code: if error_name.is_empty() {
+                    Ok(TotpCode(code))
+                } else {
+                    Err(slot_error(&error_name, &error_message))
+                },
+            }).collect())
+    }
+
+    /// Like `tpm2_totp_show`, but returns the code from an earlier call in the same window without
+    /// asking the daemon again. Errors are not cached.
+    pub fn tpm2_totp_show_cached(&self) -> Result<TotpCode, Error> {
+        if let Some((expires, code)) = self.cached_code.get() {
+            if Instant::now() < expires {
+                return Ok(TotpCode(code));
+            }
+        }
+
+        let expires = Instant::now() + Duration::from_secs(window_remaining());
+        let code = self.tpm2_totp_show()?;
+        self.cached_code.set(Some((expires, code.0)));
+        Ok(code)
+    }
+
+    /// Start receiving `SIGNAL_TPM2_TOTP_PROGRESS`, to be read with `tpm2_totp_progress`. As calls
+    /// block the connection, progress is watched with a client of its own.
+    pub fn watch_tpm2_totp_progress(&self) -> Result<(), Error> {
+        self.connection.add_match(&format!(
+            "type='signal',interface='{}',member='{}'",
+            DBUS_IFACE,
+            SIGNAL_TPM2_TOTP_PROGRESS
+        )).map_err(Error::Connection)
+    }
+
+    /// Wait up to `timeout_ms` milliseconds for the next progress signal.
+    pub fn tpm2_totp_progress(&self, timeout_ms: u32) -> Option<Progress> {
+        self.connection.incoming(timeout_ms)
+            .filter(|m| m.msg_type() == MessageType::Signal)
+            .filter(|m| m.member().map_or(false, |member| &*member == SIGNAL_TPM2_TOTP_PROGRESS))
+            .find_map(|m| {
+                let (phase, step, steps) = m.read3::<u32, u32, u32>().ok()?;
+                Some(Progress {
+                    phase: Phase::from_u32(phase)?,
+                    step,
+                    steps,
+                })
+            })
+    }
+
+    pub fn tpm_clock(&self) -> Result<TpmClock, Error> {
+        self.call_method(METHOD_TPM_CLOCK, |m| m)?
+            .read5::<u64, u64, u32, u32, bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM_CLOCK, why))
+            .map(|(time, clock, reset_count, restart_count, safe)| TpmClock {
+                time,
+                clock,
+                reset_count,
+                restart_count,
+                safe,
+            })
+    }
+
+    /// Whether the TPM's EK certificate was issued by a known manufacturer. False when the TPM has
+    /// no EK certificate.
+    pub fn tpm_ek_verified(&self) -> Result<bool, Error> {
+        self.call_method(METHOD_TPM_EK_VERIFIED, |m| m)?
+            .read1::<bool>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM_EK_VERIFIED, why))
+    }
+
+    pub fn tpm_active_banks(&self) -> Result<Vec<HashAlg>, Error> {
+        self.call_method(METHOD_TPM_ACTIVE_BANKS, |m| m)?
+            .read1::<Vec<u16>>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM_ACTIVE_BANKS, why))
+            .map(|ids| ids.into_iter().map(HashAlg::from_id).collect())
+    }
+
+    pub fn tpm_type(&self) -> Result<TpmType, Error> {
+        self.call_method(METHOD_TPM_TYPE, |m| m)?
+            .read1::<String>()
+            .map_err(|why| Error::ArgumentMismatch(METHOD_TPM_TYPE, why))
+            .map(|tpm_type| tpm_type.parse().unwrap_or(TpmType::Unknown))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_error_round_trip() {
+        let dbus = dbus::Error::from(TotpError::Unknown(0x0000_098e));
+        assert!(matches!(TotpError::try_from(dbus), Ok(TotpError::Unknown(0x0000_098e))));
+    }
+
+    #[test]
+    fn other_error_without_rc() {
+        let dbus = dbus::Error::new_custom("com.system76.PopSec.Error.Other", "no TPM found");
+        match TotpError::try_from(dbus) {
+            Ok(TotpError::Other(message)) => assert_eq!(message, "no TPM found"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,313 @@
+use std::{
+    convert::TryInto,
+    fmt::Write,
+    fs,
+    io,
+};
+
+/// TCG event log of the measurements made by the firmware during this boot.
+pub const EVENT_LOG_PATH: &str = "/sys/kernel/security/tpm0/binary_bios_measurements";
+
+const TPM2_ALG_SHA1: u16 = 0x0004;
+const TPM2_ALG_SHA256: u16 = 0x000B;
+
+const EV_S_CRTM_VERSION: u32 = 0x0000_0008;
+const EV_EFI_VARIABLE_DRIVER_CONFIG: u32 = 0x8000_0001;
+const EV_EFI_VARIABLE_BOOT: u32 = 0x8000_0002;
+const EV_EFI_VARIABLE_AUTHORITY: u32 = 0x8000_00E0;
+
+/// A measurement from the TCG event log.
+#[derive(Clone, Debug)]
+pub struct Event {
+    /// PCR the event was extended into.
+    pub pcr: u32,
+    /// Event type, such as `EV_EFI_VARIABLE_DRIVER_CONFIG`.
+    pub event_type: u32,
+    /// Digests of the event, as (algorithm, digest) pairs.
+    pub digests: Vec<(u16, Vec<u8>)>,
+    /// Event data.
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    /// The SHA256 digest if the log has one, otherwise the SHA1 digest.
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.digests.iter()
+            .find(|(alg, _)| *alg == TPM2_ALG_SHA256)
+            .or_else(|| self.digests.first())
+            .map(|(_, digest)| digest.as_slice())
+    }
+
+    /// Short human readable description of the event, such as the name of a measured EFI variable.
+    pub fn description(&self) -> String {
+        let name = match self.event_type {
+            0x0000_0001 => "POST code",
+            0x0000_0003 => "No action",
+            0x0000_0004 => "Separator",
+            0x0000_0006 => "Compact hash",
+            EV_S_CRTM_VERSION => "CRTM version",
+            0x0000_000D => "Table of devices",
+            0x0000_000E => "Non-host code",
+            EV_EFI_VARIABLE_DRIVER_CONFIG
+            | EV_EFI_VARIABLE_BOOT
+            | EV_EFI_VARIABLE_AUTHORITY => {
+                if let Some(variable) = self.efi_variable_name() {
+                    return format!("EFI variable {}", variable);
+                }
+                "EFI variable"
+            },
+            0x8000_0003 => "EFI boot services application",
+            0x8000_0004 => "EFI boot services driver",
+            0x8000_0005 => "EFI runtime services driver",
+            0x8000_0006 => "EFI GPT",
+            0x8000_0007 => "EFI action",
+            0x8000_0008 => "EFI platform firmware blob",
+            0x8000_0009 => "EFI handoff tables",
+            0x8000_000A => "EFI platform firmware blob",
+            0x8000_000B => "EFI handoff tables",
+            _ => return format!("Event 0x{:08x}", self.event_type),
+        };
+        name.to_string()
+    }
+
+    // UEFI_VARIABLE_DATA is a GUID, the name and data lengths, then the UTF-16 name
+    fn efi_variable_name(&self) -> Option<String> {
+        let length = u64::from_le_bytes(self.data.get(16..24)?.try_into().ok()?) as usize;
+        let bytes = self.data.get(32..length.checked_mul(2)?.checked_add(32)?)?;
+        let name: Vec<u16> = bytes.chunks_exact(2)
+            .map(|chunk| u16::from_le_bytes([chunk[0], chunk[1]]))
+            .collect();
+        String::from_utf16(&name).ok()
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated event log"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        self.bytes(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.bytes(4).map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn sized(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+}
+
+/// Parse a TCG event log, in either the SHA1 or the crypto agile format.
+pub fn parse(data: &[u8]) -> io::Result<Vec<Event>> {
+    let mut reader = Reader { data };
+    let mut events = Vec::new();
+
+    // The first event always uses the SHA1 format. In a crypto agile log, it lists the digest
+    // sizes used by the events that follow.
+    let mut digest_sizes = Vec::new();
+    while ! reader.data.is_empty() {
+        let pcr = reader.u32()?;
+        let event_type = reader.u32()?;
+        let digests = if digest_sizes.is_empty() {
+            vec![(TPM2_ALG_SHA1, reader.bytes(20)?.to_vec())]
+        } else {
+            // The count comes from the firmware, so it only bounds the loop and not the allocation
+            let count = reader.u32()?;
+            let mut digests = Vec::with_capacity(digest_sizes.len());
+            for _ in 0..count {
+                let alg = reader.u16()?;
+                let size = digest_sizes.iter()
+                    .find(|(id, _)| *id == alg)
+                    .map(|(_, size)| *size)
+                    .ok_or_else(|| io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unknown digest algorithm 0x{:04x}", alg)
+                    ))?;
+                digests.push((alg, reader.bytes(size)?.to_vec()));
+            }
+            digests
+        };
+        let data = reader.sized()?;
+
+        if events.is_empty() && data.starts_with(b"Spec ID Event03\0") {
+            // Skip the platform class, spec version, errata, and uintn size
+            let mut spec = Reader { data: &data[16..] };
+            spec.bytes(8)?;
+            for _ in 0..spec.u32()? {
+                let alg = spec.u16()?;
+                let size = spec.u16()? as usize;
+                digest_sizes.push((alg, size));
+            }
+        }
+
+        events.push(Event {
+            pcr,
+            event_type,
+            digests,
+            data: data.to_vec(),
+        });
+    }
+
+    Ok(events)
+}
+
+/// Read and parse the event log of this boot.
+pub fn read() -> io::Result<Vec<Event>> {
+    parse(&fs::read(EVENT_LOG_PATH)?)
+}
+
+/// One line per event extended into the PCRs selected by `pcrs`, where bit N selects PCR N.
+/// Comparing the summaries of two boots shows which measurements changed between them.
+pub fn summary(events: &[Event], pcrs: u32) -> Vec<String> {
+    events.iter()
+        .filter(|event| event.pcr < 32 && pcrs & (1 << event.pcr) != 0)
+        .map(|event| {
+            let mut digest = String::new();
+            for byte in event.digest().unwrap_or(&[]) {
+                let _ = write!(digest, "{:02x}", byte);
+            }
+            format!("PCR {}: {} ({})", event.pcr, event.description(), digest)
+        })
+        .collect()
+}
+
+/// Descriptions of the events in the current summary that are not in the previous one.
+pub fn changes(previous: &[String], current: &[String]) -> Vec<String> {
+    let mut changes = Vec::new();
+    for line in current {
+        if previous.contains(line) {
+            continue;
+        }
+        // Drop the digest, which means nothing to the user
+        let description = match line.rfind(" (") {
+            Some(index) => line[..index].to_string(),
+            None => line.clone(),
+        };
+        if ! changes.contains(&description) {
+            changes.push(description);
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An event in the SHA1 format, with a zero digest
+    fn sha1_event(pcr: u32, event_type: u32, data: &[u8]) -> Vec<u8> {
+        let mut event = Vec::new();
+        event.extend_from_slice(&pcr.to_le_bytes());
+        event.extend_from_slice(&event_type.to_le_bytes());
+        event.extend_from_slice(&[0; 20]);
+        event.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        event.extend_from_slice(data);
+        event
+    }
+
+    // The first event of a crypto agile log, announcing SHA256 digests
+    fn spec_id_event() -> Vec<u8> {
+        let mut data = b"Spec ID Event03\0".to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&TPM2_ALG_SHA256.to_le_bytes());
+        data.extend_from_slice(&32u16.to_le_bytes());
+        data.push(0);
+        sha1_event(0, 0x0000_0003, &data)
+    }
+
+    #[test]
+    fn parse_empty() {
+        assert!(parse(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_sha1_event() {
+        let events = parse(&sha1_event(7, EV_EFI_VARIABLE_DRIVER_CONFIG, b"data")).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].pcr, 7);
+        assert_eq!(events[0].data, b"data");
+    }
+
+    #[test]
+    fn parse_crypto_agile_event() {
+        let mut log = spec_id_event();
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&EV_S_CRTM_VERSION.to_le_bytes());
+        log.extend_from_slice(&1u32.to_le_bytes());
+        log.extend_from_slice(&TPM2_ALG_SHA256.to_le_bytes());
+        log.extend_from_slice(&[0xAA; 32]);
+        log.extend_from_slice(&0u32.to_le_bytes());
+        let events = parse(&log).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].digest(), Some(&[0xAA; 32][..]));
+    }
+
+    #[test]
+    fn parse_truncated_header() {
+        assert!(parse(&[0; 6]).is_err());
+    }
+
+    #[test]
+    fn parse_truncated_digest() {
+        assert!(parse(&sha1_event(0, 0, &[])[..20]).is_err());
+    }
+
+    #[test]
+    fn parse_data_size_past_end() {
+        let mut log = sha1_event(0, 0, &[]);
+        let len = log.len();
+        log[len - 4..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&log).is_err());
+    }
+
+    #[test]
+    fn parse_truncated_spec_id_event() {
+        assert!(parse(&sha1_event(0, 0x0000_0003, b"Spec ID Event03\0")).is_err());
+    }
+
+    #[test]
+    fn parse_huge_digest_count() {
+        let mut log = spec_id_event();
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&log).is_err());
+    }
+
+    #[test]
+    fn parse_unknown_digest_algorithm() {
+        let mut log = spec_id_event();
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&0u32.to_le_bytes());
+        log.extend_from_slice(&1u32.to_le_bytes());
+        log.extend_from_slice(&0x0012u16.to_le_bytes());
+        assert!(parse(&log).is_err());
+    }
+
+    #[test]
+    fn description_huge_variable_name_length() {
+        let mut data = vec![0; 16];
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
+        data.extend_from_slice(&[0; 8]);
+        let event = Event {
+            pcr: 7,
+            event_type: EV_EFI_VARIABLE_DRIVER_CONFIG,
+            digests: Vec::new(),
+            data,
+        };
+        assert_eq!(event.description(), "EFI variable");
+    }
+}
@@ -1,2 +1,6 @@
 pub mod dbus;
+pub mod event_log;
+pub mod otpauth;
+pub mod secure_boot;
+pub mod status;
 pub mod tpm2_totp;
@@ -0,0 +1,129 @@
+use sha2::{Digest, Sha256};
+
+use crate::tpm2_totp::{TotpSecret, TOTP_ALGORITHM, TOTP_PERIOD};
+
+/// Issuer shown by authenticator apps for the TPM2 TOTP secret.
+pub const ISSUER: &str = "Pop!_OS";
+
+/// Account shown by authenticator apps for the TPM2 TOTP secret, which is the system's hostname.
+pub fn account() -> String {
+    let mut buffer = [0u8; 256];
+    let res = unsafe {
+        libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len())
+    };
+    if res != 0 {
+        return "TPM2-TOTP".to_string();
+    }
+    let len = buffer.iter().position(|&x| x == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+// Percent-encode everything but unreserved characters, so the result is valid in any URL component
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char);
+            },
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+impl TotpSecret {
+    /// The otpauth URL used to add the secret to an authenticator app.
+    pub fn otpauth_url(&self, issuer: &str, account: &str) -> String {
+        let secret_b32 = self.as_base32();
+        let issuer = percent_encode(issuer);
+        format!(
+            "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits=6&period={}",
+            issuer,
+            percent_encode(account),
+            secret_b32,
+            issuer,
+            TOTP_ALGORITHM.name(),
+            TOTP_PERIOD
+        )
+    }
+
+    /// Short checksum of the secret, the first four hex digits of its SHA256. Comparing the
+    /// checksums shown at enrollment and recovery confirms the secret was transferred intact,
+    /// without revealing it.
+    pub fn checksum(&self) -> String {
+        let digest = Sha256::digest(self.as_bytes());
+        format!("{:02x}{:02x}", digest[0], digest[1])
+    }
+
+    /// The otpauth URL as a QR code drawn with unicode half blocks, for scanning from a terminal.
+    /// Higher error correction levels make the code larger, but still scannable when partially
+    /// damaged, such as when printed. `EcLevel::M` is the usual choice.
+    ///
+    /// Fails if the URL does not fit in a QR code, which needs an account name of thousands of
+    /// characters.
+    pub fn qr_utf8(
+        &self,
+        issuer: &str,
+        account: &str,
+        ec_level: qrcode::EcLevel,
+    ) -> Result<String, qrcode::types::QrError> {
+        let qr = qrcode::QrCode::with_error_correction_level(self.otpauth_url(issuer, account), ec_level)?;
+        // Inverted, as most terminals draw light text on a dark background
+        Ok(qr.render::<qrcode::render::unicode::Dense1x2>()
+            .dark_color(qrcode::render::unicode::Dense1x2::Light)
+            .light_color(qrcode::render::unicode::Dense1x2::Dark)
+            .build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The key of the RFC 6238 test vectors
+    fn secret() -> TotpSecret {
+        TotpSecret::new(b"12345678901234567890".to_vec()).unwrap()
+    }
+
+    #[test]
+    fn percent_encode_space() {
+        assert_eq!(percent_encode("my laptop"), "my%20laptop");
+    }
+
+    #[test]
+    fn percent_encode_unicode() {
+        assert_eq!(percent_encode("café"), "caf%C3%A9");
+    }
+
+    #[test]
+    fn percent_encode_separators() {
+        assert_eq!(percent_encode("user@host:1"), "user%40host%3A1");
+    }
+
+    #[test]
+    fn otpauth_url_label() {
+        let url = secret().otpauth_url(ISSUER, "my laptop");
+        assert!(url.starts_with(
+            "otpauth://totp/Pop%21_OS:my%20laptop?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Pop%21_OS&"
+        ), "{}", url);
+    }
+
+    #[test]
+    fn otpauth_url_parameters() {
+        let url = secret().otpauth_url(ISSUER, "host");
+        assert!(url.ends_with("&algorithm=SHA1&digits=6&period=30"), "{}", url);
+    }
+
+    #[test]
+    fn checksum_known_vectors() {
+        assert_eq!(secret().checksum(), "6ed6");
+        assert_eq!(TotpSecret::new(vec![0; 20]).unwrap().checksum(), "de47");
+    }
+
+    #[test]
+    fn qr_utf8_blocks() {
+        let qr = secret().qr_utf8(ISSUER, "host", qrcode::EcLevel::M).unwrap();
+        assert!(qr.contains(|c| matches!(c, '█' | '▀' | '▄')), "{}", qr);
+    }
+}
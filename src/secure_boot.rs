@@ -0,0 +1,375 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, Write},
+    mem,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+/// Directory the kernel creates when booted by EFI firmware.
+pub const EFI_DIR: &str = "/sys/firmware/efi";
+
+/// Whether the system was booted by EFI firmware. Legacy BIOS systems have no Secure Boot, which
+/// `read_state` reports the same as Secure Boot being disabled.
+pub fn efi_supported() -> bool {
+    Path::new(EFI_DIR).is_dir()
+}
+
+/// Directory where the kernel exposes EFI variables.
+pub const EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
+
+/// Vendor GUID of the variables defined by the UEFI specification, such as `SecureBoot`.
+pub const EFI_GLOBAL_VARIABLE: &str = "8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// Vendor GUID of the `db` and `dbx` signature databases.
+pub const EFI_IMAGE_SECURITY_DATABASE: &str = "d719b2cb-3d3a-4596-a3bc-dad00e67656f";
+
+/// Signature type of X.509 certificates in a signature list.
+pub const EFI_CERT_X509_GUID: &str = "a5c059a1-94e4-4aa7-87b5-ab155c2bf072";
+
+/// Signature type of SHA256 hashes in a signature list.
+pub const EFI_CERT_SHA256_GUID: &str = "c1c41626-504c-4092-aca9-41f936934328";
+
+/// Variables that make up the Secure Boot state, all with the `EFI_GLOBAL_VARIABLE` GUID.
+pub const STATE_VARIABLES: &[&str] = &["SecureBoot", "SetupMode", "AuditMode", "DeployedMode"];
+
+/// Secure Boot state, as read from the EFI variables.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SecureBootState {
+    pub secure_boot: bool,
+    pub setup_mode: bool,
+    /// Whether the firmware is in audit mode, where images failing verification are logged
+    /// instead of rejected. `None` when the firmware predates UEFI 2.5 and has no such mode.
+    pub audit_mode: Option<bool>,
+    /// Whether the firmware is in deployed mode, which can only be left by clearing the platform
+    /// key. `None` when the firmware predates UEFI 2.5 and has no such mode.
+    pub deployed_mode: Option<bool>,
+}
+
+/// Path of an EFI variable in efivarfs.
+pub fn variable_path(name: &str, guid: &str) -> PathBuf {
+    PathBuf::from(format!("{}/{}-{}", EFIVARS_DIR, name, guid))
+}
+
+/// Read the data of an EFI variable, without the attributes that efivarfs puts in front of it.
+pub fn read_variable(name: &str, guid: &str) -> io::Result<Vec<u8>> {
+    let mut data = fs::read(variable_path(name, guid))?;
+    if data.len() < 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("EFI variable {} is missing its attributes", name)
+        ));
+    }
+    data.drain(..4);
+    Ok(data)
+}
+
+/// Interpret the contents of a boolean EFI variable as read from efivarfs, where the single byte of
+/// the value follows the four bytes of the attributes. `None` when there is no value.
+pub fn parse_efi_bool(bytes: &[u8]) -> Option<bool> {
+    bytes.get(4).map(|x| *x > 0)
+}
+
+// A variable that is defined without a value is off
+fn read_optional_bool(name: &str) -> io::Result<Option<bool>> {
+    match fs::read(variable_path(name, EFI_GLOBAL_VARIABLE)) {
+        Ok(data) => Ok(Some(parse_efi_bool(&data).unwrap_or(false))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+// Firmware without Secure Boot support does not define its variables, which is the same as them
+// being off
+fn read_bool(name: &str) -> io::Result<bool> {
+    read_optional_bool(name).map(|value| value.unwrap_or(false))
+}
+
+/// Read the Secure Boot state from the EFI variables.
+pub fn read_state() -> io::Result<SecureBootState> {
+    Ok(SecureBootState {
+        secure_boot: read_bool("SecureBoot")?,
+        setup_mode: read_bool("SetupMode")?,
+        audit_mode: read_optional_bool("AuditMode")?,
+        deployed_mode: read_optional_bool("DeployedMode")?,
+    })
+}
+
+// GUIDs are stored with their first three fields in little endian
+fn guid(bytes: &[u8]) -> String {
+    let mut guid = String::new();
+    for (i, index) in [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15].iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            guid.push('-');
+        }
+        let _ = write!(guid, "{:02x}", bytes[*index]);
+    }
+    guid
+}
+
+/// An entry of a signature database, such as a certificate or an image hash.
+#[derive(Clone, Debug)]
+pub struct Signature {
+    /// GUID of the signature's owner, such as the OS vendor.
+    pub owner: String,
+    /// The certificate or hash.
+    pub data: Vec<u8>,
+}
+
+/// A list of signatures of the same type, such as `EFI_CERT_X509_GUID`.
+#[derive(Clone, Debug)]
+pub struct SignatureList {
+    pub signature_type: String,
+    pub signatures: Vec<Signature>,
+}
+
+/// Parse the `EFI_SIGNATURE_LIST` structures that make up a signature database.
+pub fn parse_signature_lists(mut data: &[u8]) -> io::Result<Vec<SignatureList>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid EFI signature list");
+    let u32_at = |data: &[u8], offset: usize| {
+        u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]) as usize
+    };
+
+    let mut lists = Vec::new();
+    while ! data.is_empty() {
+        if data.len() < 28 {
+            return Err(invalid());
+        }
+        let list_size = u32_at(data, 16);
+        let header_size = u32_at(data, 20);
+        let signature_size = u32_at(data, 24);
+        if list_size > data.len() || signature_size < 16 || 28 + header_size > list_size {
+            return Err(invalid());
+        }
+
+        let signatures = data[28 + header_size..list_size].chunks(signature_size)
+            .map(|chunk| if chunk.len() == signature_size {
+                Ok(Signature {
+                    owner: guid(&chunk[..16]),
+                    data: chunk[16..].to_vec(),
+                })
+            } else {
+                Err(invalid())
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        lists.push(SignatureList {
+            signature_type: guid(&data[..16]),
+            signatures,
+        });
+
+        data = &data[list_size..];
+    }
+    Ok(lists)
+}
+
+/// A Secure Boot key variable and its contents.
+#[derive(Clone, Debug)]
+pub struct KeyVariable {
+    /// Name of the variable: `PK`, `KEK`, `db`, or `dbx`.
+    pub name: &'static str,
+    pub lists: Vec<SignatureList>,
+}
+
+impl KeyVariable {
+    /// Total number of signatures in the variable.
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(|list| list.signatures.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Read the Secure Boot key variables, skipping those that are not defined, such as the platform
+/// key while in setup mode.
+pub fn keys() -> io::Result<Vec<KeyVariable>> {
+    let mut keys = Vec::new();
+    for &(name, guid) in &[
+        ("PK", EFI_GLOBAL_VARIABLE),
+        ("KEK", EFI_GLOBAL_VARIABLE),
+        ("db", EFI_IMAGE_SECURITY_DATABASE),
+        ("dbx", EFI_IMAGE_SECURITY_DATABASE),
+    ] {
+        let data = match read_variable(name, guid) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        keys.push(KeyVariable {
+            name,
+            lists: parse_signature_lists(&data)?,
+        });
+    }
+    Ok(keys)
+}
+
+/// SHA256 of the `PK`, `KEK`, and `db` variables, in hex, to tell whether the Secure Boot keys
+/// changed since it was taken. `dbx` is left out, as revocation updates are expected. Undefined
+/// variables, as in setup mode, are hashed as empty.
+pub fn keys_digest() -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    for &(name, guid) in &[
+        ("PK", EFI_GLOBAL_VARIABLE),
+        ("KEK", EFI_GLOBAL_VARIABLE),
+        ("db", EFI_IMAGE_SECURITY_DATABASE),
+    ] {
+        let data = match read_variable(name, guid) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        // The length keeps data from moving between variables without changing the digest
+        hasher.update(name.as_bytes());
+        hasher.update(&(data.len() as u64).to_le_bytes());
+        hasher.update(&data);
+    }
+    let mut digest = String::new();
+    for byte in hasher.finalize() {
+        let _ = write!(digest, "{:02x}", byte);
+    }
+    Ok(digest)
+}
+
+/// The Pop!_OS signing certificate, in DER format, which must be in `db` for the OS to boot with
+/// Secure Boot enabled.
+pub const OS_DB_CERT: &str = "/usr/share/popsec/keys/db.der";
+
+/// Whether the certificate at `OS_DB_CERT` is enrolled in `db`, so that Pop!_OS boots under the
+/// current keys. An undefined `db`, as in setup mode, has no certificates enrolled. `None` when
+/// the certificate is not installed, so there is nothing to check.
+pub fn os_keys_enrolled() -> io::Result<Option<bool>> {
+    let expected = match fs::read(OS_DB_CERT) {
+        Ok(ok) => ok,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(io::Error::new(
+            err.kind(),
+            format!("failed to read {}: {}", OS_DB_CERT, err)
+        )),
+    };
+    let db = match read_variable("db", EFI_IMAGE_SECURITY_DATABASE) {
+        Ok(ok) => ok,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Some(false)),
+        Err(err) => return Err(err),
+    };
+    Ok(Some(parse_signature_lists(&db)?.iter()
+        .filter(|list| list.signature_type == EFI_CERT_X509_GUID)
+        .flat_map(|list| list.signatures.iter())
+        .any(|signature| signature.data == expected)))
+}
+
+/// Directory holding the default keys, as `PK.auth`, `KEK.auth`, and `db.auth` files in the signed
+/// `EFI_VARIABLE_AUTHENTICATION_2` format produced by `sign-efi-sig-list`.
+pub const DEFAULT_KEYS_DIR: &str = "/usr/share/popsec/keys";
+
+// Non volatile, boot service and runtime access, with time based authenticated writes
+const KEY_ATTRIBUTES: u32 = 0x0000_0001 | 0x0000_0002 | 0x0000_0004 | 0x0000_0020;
+
+// _IOR('f', 1, long) and _IOW('f', 2, long) from linux/fs.h, which pass an int despite their size
+const FS_IOC_GETFLAGS: libc::c_ulong = (2 << 30) | ((mem::size_of::<libc::c_long>() as libc::c_ulong) << 16) | (0x66 << 8) | 1;
+const FS_IOC_SETFLAGS: libc::c_ulong = (1 << 30) | ((mem::size_of::<libc::c_long>() as libc::c_ulong) << 16) | (0x66 << 8) | 2;
+const FS_IMMUTABLE_FL: libc::c_int = 0x0000_0010;
+
+// efivarfs makes existing variables immutable, so that they are not deleted by accident, and they
+// cannot be opened for writing until the flag is cleared
+fn clear_immutable(path: &Path) -> io::Result<()> {
+    let file = match fs::File::open(path) {
+        Ok(ok) => ok,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & FS_IMMUTABLE_FL != 0 {
+        flags &= !FS_IMMUTABLE_FL;
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_SETFLAGS as _, &flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn write_key(name: &str, guid: &str, auth: &Path) -> io::Result<()> {
+    let data = fs::read(auth).map_err(|err| io::Error::new(
+        err.kind(),
+        format!("failed to read {}: {}", auth.display(), err)
+    ))?;
+    let mut buffer = KEY_ATTRIBUTES.to_le_bytes().to_vec();
+    buffer.extend_from_slice(&data);
+    let path = variable_path(name, guid);
+    clear_immutable(&path).and_then(|()| {
+        // efivarfs requires the whole variable in a single write
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(&path)?
+            .write_all(&buffer)
+    }).map_err(|err| io::Error::new(
+        err.kind(),
+        format!("failed to write EFI variable {}: {}", name, err)
+    ))
+}
+
+// Enrolled in this order, as enrolling the platform key leaves setup mode
+const DEFAULT_KEYS: [(&str, &str); 3] = [
+    ("db", EFI_IMAGE_SECURITY_DATABASE),
+    ("KEK", EFI_GLOBAL_VARIABLE),
+    ("PK", EFI_GLOBAL_VARIABLE),
+];
+
+/// Whether every default key is installed in `DEFAULT_KEYS_DIR`, so that `enroll_default_keys`
+/// can succeed in setup mode.
+pub fn default_keys_available() -> bool {
+    let dir = Path::new(DEFAULT_KEYS_DIR);
+    DEFAULT_KEYS.iter().all(|(name, _guid)| dir.join(format!("{}.auth", name)).is_file())
+}
+
+/// Enroll the default keys from `DEFAULT_KEYS_DIR`, which takes the firmware out of setup mode and
+/// into user mode. Refuses to run when not in setup mode, so enrolled keys are never replaced.
+pub fn enroll_default_keys() -> io::Result<()> {
+    if ! read_state()?.setup_mode {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Secure Boot is not in setup mode"
+        ));
+    }
+
+    let dir = Path::new(DEFAULT_KEYS_DIR);
+    for (name, guid) in DEFAULT_KEYS.iter() {
+        write_key(name, guid, &dir.join(format!("{}.auth", name)))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_efi_bool_empty() {
+        assert_eq!(parse_efi_bool(&[]), None);
+    }
+
+    #[test]
+    fn parse_efi_bool_attributes_only() {
+        assert_eq!(parse_efi_bool(&[0; 4]), None);
+    }
+
+    #[test]
+    fn parse_efi_bool_off() {
+        assert_eq!(parse_efi_bool(&[6, 0, 0, 0, 0]), Some(false));
+    }
+
+    #[test]
+    fn parse_efi_bool_on() {
+        assert_eq!(parse_efi_bool(&[6, 0, 0, 0, 1]), Some(true));
+    }
+}
@@ -0,0 +1,116 @@
+#[cfg(feature = "serde")]
+use serde::{Serialize, Serializer};
+
+use std::str::FromStr;
+
+use crate::tpm2_totp::{TotpCode, TotpError};
+
+pub use crate::secure_boot::SecureBootState;
+
+/// State of the TPM2 TOTP secret, suitable for reporting to other tools.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct TotpStatus {
+    /// Whether a TOTP secret is stored in the TPM.
+    pub enrolled: bool,
+    /// The current code, serialized as a zero-padded string to preserve leading zeros.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_code"))]
+    pub code: Option<TotpCode>,
+    /// Description of the error that prevented calculating a code.
+    pub error: Option<String>,
+}
+
+impl TotpStatus {
+    pub fn new(result: Result<TotpCode, TotpError>) -> Self {
+        match result {
+            Ok(code) => Self {
+                enrolled: true,
+                code: Some(code),
+                error: None,
+            },
+            Err(err) => Self {
+                enrolled: ! matches!(err, TotpError::SecretNotFound),
+                code: None,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+/// Secure Boot and TPM state, for rendering a whole panel from a single call to the daemon.
+#[derive(Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct SecurityStatus {
+    pub secure_boot: bool,
+    pub setup_mode: bool,
+    /// Whether a TPM is available to the daemon.
+    pub tpm_present: bool,
+    /// Whether a TOTP secret is stored in the TPM.
+    pub enrolled: bool,
+}
+
+/// Whether the TPM2 TOTP secret still unseals, for monitoring. Unlike `TotpStatus`, this never
+/// includes the code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Health {
+    Ok,
+    SystemStateChanged,
+    SecretNotFound,
+    TpmUnavailable,
+    Error,
+}
+
+impl Health {
+    pub fn new(result: &Result<TotpCode, TotpError>) -> Self {
+        match result {
+            Ok(_) => Self::Ok,
+            Err(TotpError::SystemStateChanged) => Self::SystemStateChanged,
+            Err(TotpError::SecretNotFound) => Self::SecretNotFound,
+            Err(TotpError::NotStarted) => Self::TpmUnavailable,
+            Err(_) => Self::Error,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::SystemStateChanged => "system-state-changed",
+            Self::SecretNotFound => "secret-not-found",
+            Self::TpmUnavailable => "tpm-unavailable",
+            Self::Error => "error",
+        }
+    }
+
+    /// Exit code following the Nagios plugin convention: 0 for OK, 1 for WARNING, 2 for
+    /// CRITICAL, and 3 for UNKNOWN.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Ok => 0,
+            Self::SystemStateChanged => 1,
+            Self::SecretNotFound | Self::TpmUnavailable => 2,
+            Self::Error => 3,
+        }
+    }
+}
+
+impl FromStr for Health {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "ok" => Ok(Self::Ok),
+            "system-state-changed" => Ok(Self::SystemStateChanged),
+            "secret-not-found" => Ok(Self::SecretNotFound),
+            "tpm-unavailable" => Ok(Self::TpmUnavailable),
+            "error" => Ok(Self::Error),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+fn serialize_code<S: Serializer>(code: &Option<TotpCode>, serializer: S) -> Result<S::Ok, S::Error> {
+    match code {
+        Some(code) => serializer.serialize_some(&format!("{:06}", code.0)),
+        None => serializer.serialize_none(),
+    }
+}
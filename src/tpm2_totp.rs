@@ -1,8 +1,21 @@
 use std::{
+    env,
     ffi::CString,
+    fmt::Write as _,
+    fs,
+    io::Write,
+    mem,
+    path::PathBuf,
     ptr,
     slice,
+    str::FromStr,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use hmac::{Hmac, Mac, NewMac};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use thiserror::Error;
 use tss_esapi::{
     tcti_ldr::{
@@ -10,8 +23,175 @@ use tss_esapi::{
         TctiContext,
         TctiNameConf
     },
+    tss2_esys::{
+        ESYS_CONTEXT,
+        ESYS_TR,
+        ESYS_TR_NONE,
+        ESYS_TR_PASSWORD,
+        ESYS_TR_RH_ENDORSEMENT,
+        Esys_CreatePrimary,
+        Esys_Finalize,
+        Esys_FlushContext,
+        Esys_GetCapability,
+        Esys_Initialize,
+        Esys_NV_Read,
+        Esys_NV_ReadPublic,
+        Esys_PCR_Read,
+        Esys_Quote,
+        Esys_ReadClock,
+        Esys_TR_FromTPMPublic,
+        TPM2B_ATTEST,
+        TPM2B_DATA,
+        TPM2B_NAME,
+        TPM2B_NV_PUBLIC,
+        TPM2B_PRIVATE,
+        TPM2B_PUBLIC,
+        TPM2B_SENSITIVE_CREATE,
+        TPML_DIGEST,
+        TPML_PCR_SELECTION,
+        TPMI_YES_NO,
+        TPMS_CAPABILITY_DATA,
+        TPMS_TIME_INFO,
+        TPMT_SIG_SCHEME,
+        TPMT_SIGNATURE,
+        Tss2_MU_TPM2B_ATTEST_Marshal,
+        Tss2_MU_TPM2B_PUBLIC_Marshal,
+        Tss2_MU_TPM2B_PUBLIC_Unmarshal,
+        Tss2_MU_TPML_PCR_SELECTION_Marshal,
+        Tss2_MU_TPMT_SIGNATURE_Marshal,
+        Tss2_MU_UINT32_Unmarshal,
+    },
 };
 use tpm2_totp_sys::*;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::secure_boot;
+
+/// NV indices where TPM manufacturers store the endorsement key certificates, of the RSA 2048 and
+/// the ECC NIST P-256 keys.
+pub const EK_CERT_INDICES: [u32; 2] = [0x01C0_0002, 0x01C0_000A];
+
+/// Certificates of TPM manufacturers' certificate authorities, in DER or PEM format, that EK
+/// certificates are verified against. TPMs only hold the EK certificate itself, so intermediate
+/// certificates go here as well.
+pub const EK_ROOTS_DIR: &str = "/usr/share/popsec/ek-roots";
+
+/// Whether `cert`, an EK certificate in DER format, was issued by a certificate authority in
+/// `EK_ROOTS_DIR`. Without that directory, no certificate is verified.
+pub fn verify_ek_certificate(cert: &[u8]) -> Result<bool, TotpError> {
+    use openssl::{
+        error::ErrorStack,
+        stack::Stack,
+        x509::{store::X509StoreBuilder, verify::X509VerifyFlags, X509StoreContext, X509},
+    };
+    let other = |err: ErrorStack| TotpError::Other(format!(
+        "tpm2-totp: failed to verify EK certificate: {}", err
+    ));
+    let io_other = |err: std::io::Error| TotpError::Other(format!(
+        "tpm2-totp: failed to read {}: {}", EK_ROOTS_DIR, err
+    ));
+
+    let cert = X509::from_der(cert).map_err(other)?;
+    let mut store = X509StoreBuilder::new().map_err(other)?;
+    // EK certificates have an empty subject, naming the TPM in a critical subject alternative name
+    // that OpenSSL does not understand. Intermediates are trusted like the roots they come with.
+    store.set_flags(X509VerifyFlags::IGNORE_CRITICAL | X509VerifyFlags::PARTIAL_CHAIN).map_err(other)?;
+    let entries = match fs::read_dir(EK_ROOTS_DIR) {
+        Ok(ok) => ok,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(err) => return Err(io_other(err)),
+    };
+    for entry in entries {
+        let data = fs::read(entry.map_err(io_other)?.path()).map_err(io_other)?;
+        let certs = match X509::stack_from_pem(&data) {
+            Ok(certs) if ! certs.is_empty() => certs,
+            _ => X509::from_der(&data).into_iter().collect(),
+        };
+        for ca in certs {
+            // Certificates listed twice are not an error
+            let _ = store.add_cert(ca);
+        }
+    }
+    let store = store.build();
+
+    let chain = Stack::new().map_err(other)?;
+    let mut context = X509StoreContext::new().map_err(other)?;
+    context.init(&store, &cert, &chain, |context| context.verify_cert()).map_err(other)
+}
+
+/// Length in seconds of a TOTP window, as used by tpm2-totp.
+pub const TOTP_PERIOD: u64 = 30;
+
+/// HMAC algorithm of the TOTP. tpm2-totp creates every key with HMAC-SHA1 and has no way to
+/// choose another, which is also the only algorithm every authenticator app supports.
+pub const TOTP_ALGORITHM: HashAlg = HashAlg::Sha1;
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+/// Seconds remaining until the current TOTP window ends.
+pub fn window_remaining() -> u64 {
+    TOTP_PERIOD - unix_time() % TOTP_PERIOD
+}
+
+/// Whether a TPM is available, either as a kernel device or through a TCTI set in the environment.
+pub fn tpm_available() -> bool {
+    Tpm2Totp::TCTI_VARS.iter().any(|var| env::var_os(var).is_some())
+        || fs::metadata("/sys/class/tpm/tpm0").is_ok()
+}
+
+/// Whether the kernel is in FIPS mode, where the SHA1 PCR bank should not be used.
+pub fn fips_enabled() -> bool {
+    fs::read_to_string("/proc/sys/crypto/fips_enabled")
+        .map_or(false, |value| value.trim() == "1")
+}
+
+/// Calculate the current code directly with the TPM and tear the context down again, for scripts
+/// and helpers that run once. Nothing is printed, the code is returned zero-padded.
+pub fn current_code() -> Result<String, TotpError> {
+    let code = Tpm2Totp::new()?.show()?;
+    Ok(format!("{:06}", code.0))
+}
+
+/// Entry point for PAM modules and other login helpers, to compare a code entered at login with the
+/// TPM's. Unlike `current_code`, it never falls back to the tpm2-totp command, so it prompts for
+/// nothing, spawns no process, and writes nothing to stdout. Apart from the TSS library's own
+/// logging, controlled with `TSS2_LOG`, it has no side effects. Returns the code, to be compared
+/// with `TotpCode::verify`, or `None` when no code can be calculated, in which case login must be
+/// denied.
+pub fn pam_current_code() -> Option<TotpCode> {
+    Tpm2Totp::new().ok()?.show_ffi().ok()
+}
+
+/// Number of times a TPM command is retried while the TPM reports a transient condition, after
+/// which `TotpError::TpmBusy` is returned.
+pub const MAX_RETRIES: u32 = 5;
+
+// Run a TPM command, retrying with a jittered backoff while the TPM asks for it to be retried,
+// which happens when it is busy with another command or with its self test
+fn retry<T: Copy + Into<i64>, F: FnMut() -> T>(mut command: F) -> T {
+    use tss_esapi::constants::tss::*;
+    let mut attempt = 0;
+    loop {
+        let rc = command();
+        // Ignore the layer, as the resource manager passes these along from the TPM
+        let code = (rc.into() as u32) & 0xFFFF;
+        let retryable = code == TPM2_RC_RETRY || code == TPM2_RC_YIELDED || code == TPM2_RC_TESTING;
+        if ! retryable || attempt >= MAX_RETRIES {
+            return rc;
+        }
+
+        // Spread out retries from concurrent clients, without pulling in a random number generator
+        let jitter = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| u64::from(x.subsec_nanos()) % 25);
+        thread::sleep(Duration::from_millis((25 << attempt) + jitter));
+        attempt += 1;
+    }
+}
 
 struct AutoFree<T>(*mut T);
 
@@ -24,7 +204,88 @@ impl<T> Drop for AutoFree<T> {
     }
 }
 
-#[derive(Debug, Error)]
+// ESAPI context layered on top of an existing TCTI context, which it does not take ownership of
+struct EsysContext(*mut ESYS_CONTEXT);
+
+impl Drop for EsysContext {
+    fn drop(&mut self) {
+        if ! self.0.is_null() {
+            unsafe { Esys_Finalize(&mut self.0); }
+        }
+    }
+}
+
+// Select the given PCRs in the SHA256 bank
+fn pcr_selection(pcrs: u32) -> TPML_PCR_SELECTION {
+    use tss_esapi::constants::tss::*;
+    let mut selection: TPML_PCR_SELECTION = unsafe { mem::zeroed() };
+    selection.count = 1;
+    selection.pcrSelections[0].hash = TPM2_ALG_SHA256;
+    selection.pcrSelections[0].sizeofSelect = 3;
+    selection.pcrSelections[0].pcrSelect[0] = pcrs as u8;
+    selection.pcrSelections[0].pcrSelect[1] = (pcrs >> 8) as u8;
+    selection.pcrSelections[0].pcrSelect[2] = (pcrs >> 16) as u8;
+    selection
+}
+
+// Select the given PCRs in each bank of a `BANK_*` mask, in the order tpm2-totp seals to them
+fn bank_pcr_selection(pcrs: u32, banks: u32) -> TPML_PCR_SELECTION {
+    use tss_esapi::constants::tss::*;
+    let mut selection: TPML_PCR_SELECTION = unsafe { mem::zeroed() };
+    for &(bank, hash) in &[
+        (Tpm2Totp::BANK_SHA1, TPM2_ALG_SHA1),
+        (Tpm2Totp::BANK_SHA256, TPM2_ALG_SHA256),
+        (Tpm2Totp::BANK_SHA384, TPM2_ALG_SHA384),
+    ] {
+        if banks & bank == 0 {
+            continue;
+        }
+        let pcr_selection = &mut selection.pcrSelections[selection.count as usize];
+        pcr_selection.hash = hash;
+        pcr_selection.sizeofSelect = 3;
+        pcr_selection.pcrSelect[0] = pcrs as u8;
+        pcr_selection.pcrSelect[1] = (pcrs >> 8) as u8;
+        pcr_selection.pcrSelect[2] = (pcrs >> 16) as u8;
+        selection.count += 1;
+    }
+    selection
+}
+
+// Header of a tpm2-totp key blob, which starts with the blob format version, the PCR and bank
+// masks the key is sealed to, and the public area of the sealed key
+struct KeyInfo {
+    pcrs: u32,
+    banks: u32,
+    public: TPM2B_PUBLIC,
+}
+
+impl KeyInfo {
+    fn parse(blob: &[u8]) -> Result<Self, TotpError> {
+        unsafe {
+            let mut offset = 0;
+            let mut version = 0;
+            let mut pcrs = 0;
+            let mut banks = 0;
+            let mut public: TPM2B_PUBLIC = mem::zeroed();
+            let mut rc = Tss2_MU_UINT32_Unmarshal(blob.as_ptr(), blob.len() as _, &mut offset, &mut version);
+            if rc == 0 {
+                rc = Tss2_MU_UINT32_Unmarshal(blob.as_ptr(), blob.len() as _, &mut offset, &mut pcrs);
+            }
+            if rc == 0 {
+                rc = Tss2_MU_UINT32_Unmarshal(blob.as_ptr(), blob.len() as _, &mut offset, &mut banks);
+            }
+            if rc == 0 {
+                rc = Tss2_MU_TPM2B_PUBLIC_Unmarshal(blob.as_ptr(), blob.len() as _, &mut offset, &mut public);
+            }
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+            Ok(Self { pcrs, banks, public })
+        }
+    }
+}
+
+#[derive(Clone, Debug, Error)]
 pub enum TotpError {
     #[error("No recovery password for the TOTP secret was given")]
     NoPasswordProvided,
@@ -40,17 +301,482 @@ pub enum TotpError {
     WrongPassword,
     #[error("The password has been entered wrongly too many times and the TPM is in lockout mode")]
     Lockout,
-    //TODO: wrap this up too
+    #[error("The password contains invalid characters")]
+    InvalidPassword,
+    #[error("The TPM has no NV space left to store the TOTP secret, unused NV indices must be freed")]
+    NvSpaceFull,
+    #[error("The NV index of the TOTP secret is write locked until the next reboot")]
+    NvLocked,
+    #[error("The TPM has not been started, reboot or run tpm2_startup")]
+    NotStarted,
+    #[error("The NV index for the TOTP secret is used by another application")]
+    IndexInUse,
+    #[error("The stored TOTP secret is corrupted, it must be set up again")]
+    CorruptBlob,
+    #[error("Maintenance is in progress, no TOTP is calculated until it is finished")]
+    Maintenance,
+    #[error("The TPM is busy or out of memory, close other applications using the TPM and try again")]
+    TpmBusy,
+    #[error("The TOTP secret does not have the expected length")]
+    InvalidSecret,
+    #[error("Unknown TPM error (rc=0x{0:08x})")]
+    Unknown(u32),
     #[error("{0}")]
     Other(String),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TotpCode(pub u64);
 
+/// The code of one of several secrets, as returned by `Tpm2Totp::show_all`.
+#[derive(Debug)]
+pub struct SlotCode {
+    /// NV index holding the secret.
+    pub nv_index: u32,
+    /// Mask of PCRs the secret is bound to, which tells the secrets apart.
+    pub pcrs: u32,
+    /// The code, or why it could not be calculated, such as `SystemStateChanged` when the PCRs
+    /// the secret is bound to have changed.
+    pub code: Result<TotpCode, TotpError>,
+}
+
+impl TotpCode {
+    /// Check a code entered by the user, comparing in constant time to not reveal matching digits.
+    pub fn verify(&self, input: &str) -> bool {
+        let expected = format!("{:06}", self.0);
+        let input = input.trim();
+        if input.len() != expected.len() {
+            return false;
+        }
+        expected.bytes()
+            .zip(input.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// The code with a thin space between groups of three digits, such as "123 456", for display.
+    /// Anything meant to be pasted should use the ungrouped `{:06}` form.
+    pub fn formatted_grouped(&self) -> String {
+        let digits = format!("{:06}", self.0);
+        let (first, second) = digits.split_at(digits.len() - 3);
+        format!("{}\u{2009}{}", first, second)
+    }
+}
+
 pub struct TotpPass(pub String);
 
-pub struct TotpSecret(pub Vec<u8>);
+impl TotpPass {
+    // Passwords are handed to tpm2-totp as C strings, so embedded NUL bytes cannot be represented
+    fn to_cstring(&self) -> Result<CString, TotpError> {
+        CString::new(self.0.as_str()).map_err(|_| TotpError::InvalidPassword)
+    }
+
+    /// Combine the passwords of two administrators into the password the secret is sealed with, so
+    /// that recovering it needs both. The password is the XOR of the hashes of the two, so neither
+    /// tells anything about it alone. It is 32 hex digits, as the TPM limits passwords to the size
+    /// of a SHA256 digest.
+    pub fn split(first: &TotpPass, second: &TotpPass) -> TotpPass {
+        let hash = |password: &TotpPass| {
+            let mut hasher = Sha256::new();
+            hasher.update(b"popsec split password\0");
+            hasher.update(password.0.as_bytes());
+            hasher.finalize()
+        };
+        let (mut first, mut second) = (hash(first), hash(second));
+        let mut password = String::with_capacity(32);
+        for (a, b) in first.iter().zip(second.iter()).take(16) {
+            let _ = write!(password, "{:02x}", a ^ b);
+        }
+        first.as_mut_slice().zeroize();
+        second.as_mut_slice().zeroize();
+        TotpPass(password)
+    }
+}
+
+/// Length in bytes of the HMAC-SHA1 keys generated by tpm2-totp.
+pub const SECRET_LEN: usize = 20;
+
+/// The raw TOTP secret, which can only be created through `new` so that its length is checked.
+pub struct TotpSecret(Vec<u8>);
+
+impl Zeroize for TotpSecret {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+// Do not leave the seed behind in freed memory
+impl Drop for TotpSecret {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl TotpSecret {
+    /// Wrap a secret, checking that it has the length of the keys generated by tpm2-totp.
+    pub fn new(secret: Vec<u8>) -> Result<Self, TotpError> {
+        if secret.len() != SECRET_LEN {
+            return Err(TotpError::InvalidSecret);
+        }
+        Ok(Self(secret))
+    }
+
+    /// The raw bytes of the secret.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Length of the secret in bytes.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The secret in unpadded RFC 4648 base32, as entered into authenticator apps.
+    pub fn as_base32(&self) -> String {
+        base32::encode(base32::Alphabet::RFC4648 { padding: false }, &self.0)
+    }
+
+    /// The secret in base32 as in `otpauth_url`, in space separated groups of four characters such
+    /// as `JBSW Y3DP EHPK 3PXP`, which authenticator apps accept when setting up by hand.
+    pub fn as_base32_groups(&self) -> String {
+        let base32 = Zeroizing::new(self.as_base32());
+        let mut groups = String::with_capacity(base32.len() + base32.len() / 4);
+        for (i, c) in base32.chars().enumerate() {
+            if i > 0 && i % 4 == 0 {
+                groups.push(' ');
+            }
+            groups.push(c);
+        }
+        groups
+    }
+
+    /// The code for the TOTP window containing `time`, in seconds since the Unix epoch, computed as
+    /// in RFC 6238 with HMAC-SHA1 and 6 digits, like tpm2-totp.
+    pub fn code_at(&self, time: u64) -> TotpCode {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.0)
+            .expect("HMAC accepts keys of any length");
+        mac.update(&(time / TOTP_PERIOD).to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+        let offset = (hash[hash.len() - 1] & 0xf) as usize;
+        let value = u32::from_be_bytes([
+            hash[offset] & 0x7f,
+            hash[offset + 1],
+            hash[offset + 2],
+            hash[offset + 3],
+        ]);
+        TotpCode(u64::from(value % 1_000_000))
+    }
+}
+
+/// The TPM's clock information, as reported by `TPM2_ReadClock`.
+#[derive(Debug)]
+pub struct TpmClock {
+    /// Milliseconds since the last TPM reset or restart.
+    pub time: u64,
+    /// Milliseconds the TPM has been powered, which is only advanced by the TPM itself.
+    pub clock: u64,
+    /// Number of TPM resets since the TPM was cleared, incremented by a reboot.
+    pub reset_count: u32,
+    /// Number of TPM restarts since the last reset, incremented by resume from hibernation.
+    pub restart_count: u32,
+    /// Whether the clock is guaranteed to not have been rolled back. It is not after the TPM lost
+    /// power without an orderly shutdown, until the clock is next saved to NV memory. While it is
+    /// not, the TPM cannot vouch for time based results such as TOTP codes.
+    pub safe: bool,
+}
+
+/// Information about the TPM and the TPM2 TOTP secret, collected for bug reports.
+#[derive(Debug)]
+pub struct Diagnostics {
+    /// TPM vendor ID, such as `IFX` or `INTC`.
+    pub manufacturer: String,
+    /// Vendor specific firmware version, from the two firmware version properties.
+    pub firmware_version: u64,
+    /// Whether a TOTP secret is stored in the TPM.
+    pub enrolled: bool,
+    /// Mask of PCRs the secret is sealed to, or 0 when not enrolled.
+    pub pcrs: u32,
+    /// Mask of PCR banks the secret is sealed to, or 0 when not enrolled.
+    pub banks: u32,
+    /// The TPM's clock information.
+    pub clock: TpmClock,
+    /// Current count of authorization failures.
+    pub lockout_counter: u32,
+    /// Number of authorization failures before the TPM enters lockout.
+    pub max_auth_fail: u32,
+    /// Seconds before an authorization failure is forgotten.
+    pub lockout_interval: u32,
+    /// Seconds before a lockout authorization can be attempted again after a failure.
+    pub lockout_recovery: u32,
+    /// Whether the TPM is in lockout.
+    pub in_lockout: bool,
+    /// Durations of the last operations, which some TPMs take several seconds for.
+    pub timings: Timings,
+}
+
+impl Diagnostics {
+    /// Format as text for pasting into a bug report. Diagnostics hold no secrets, codes, or
+    /// passwords, so nothing is left to redact beyond not adding any. The Secure Boot state is
+    /// readable without privileges, so it is read here instead of being sent by the daemon.
+    pub fn to_report(&self) -> String {
+        let mask = |mask: u32, names: &dyn Fn(u32) -> String| -> String {
+            let list: Vec<String> = (0..32).filter(|bit| mask & (1 << bit) != 0).map(names).collect();
+            if list.is_empty() {
+                "none".to_string()
+            } else {
+                list.join(", ")
+            }
+        };
+        let bank = |bit: u32| match 1 << bit {
+            Tpm2Totp::BANK_SHA1 => "SHA1".to_string(),
+            Tpm2Totp::BANK_SHA256 => "SHA256".to_string(),
+            Tpm2Totp::BANK_SHA384 => "SHA384".to_string(),
+            _ => format!("bank {}", bit),
+        };
+        let millis = |duration: Option<Duration>| match duration {
+            Some(duration) => format!("{} ms", duration.as_millis()),
+            None => "not measured".to_string(),
+        };
+
+        let mut report = String::new();
+        let _ = writeln!(report, "TPM manufacturer: {}", self.manufacturer);
+        let tpm_type = TpmType::from_manufacturer(&self.manufacturer);
+        let _ = writeln!(report, "TPM type: {}", tpm_type.as_str());
+        let _ = writeln!(report, "TPM firmware version: 0x{:016x}", self.firmware_version);
+        let _ = writeln!(report, "TOTP enrolled: {}", self.enrolled);
+        if self.enrolled {
+            let _ = writeln!(report, "TOTP PCRs: {}", mask(self.pcrs, &|bit| bit.to_string()));
+            let _ = writeln!(report, "TOTP PCR banks: {}", mask(self.banks, &bank));
+        }
+        let _ = writeln!(report, "TPM uptime: {} s", self.clock.time / 1000);
+        let _ = writeln!(report, "TPM clock: {} s", self.clock.clock / 1000);
+        let _ = writeln!(report, "TPM reset count: {}", self.clock.reset_count);
+        let _ = writeln!(report, "TPM restart count: {}", self.clock.restart_count);
+        let _ = writeln!(report, "TPM clock safe: {}", self.clock.safe);
+        let _ = writeln!(report, "Lockout: {}", self.in_lockout);
+        let _ = writeln!(
+            report,
+            "Authorization failures: {} of {}",
+            self.lockout_counter,
+            self.max_auth_fail
+        );
+        let _ = writeln!(report, "Lockout interval: {} s", self.lockout_interval);
+        let _ = writeln!(report, "Lockout recovery: {} s", self.lockout_recovery);
+        let _ = writeln!(report, "Last init: {}", millis(self.timings.init));
+        let _ = writeln!(report, "Last reseal: {}", millis(self.timings.reseal));
+        let _ = writeln!(report, "Last show: {}", millis(self.timings.show));
+        match secure_boot::read_state() {
+            Ok(_) if ! secure_boot::efi_supported() => {
+                let _ = writeln!(report, "Secure Boot: not an EFI system");
+            },
+            Ok(state) => {
+                let optional = |value: Option<bool>| {
+                    value.map_or("unsupported".to_string(), |x| x.to_string())
+                };
+                let _ = writeln!(report, "Secure Boot: {}", state.secure_boot);
+                let _ = writeln!(report, "Setup mode: {}", state.setup_mode);
+                let _ = writeln!(report, "Audit mode: {}", optional(state.audit_mode));
+                let _ = writeln!(report, "Deployed mode: {}", optional(state.deployed_mode));
+            },
+            Err(err) => {
+                let _ = writeln!(report, "Secure Boot: failed to read state: {}", err);
+            }
+        }
+        report
+    }
+}
+
+/// Durations of the last operations on the TPM, or `None` for those not done since the instance
+/// was created.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    pub init: Option<Duration>,
+    pub reseal: Option<Duration>,
+    pub show: Option<Duration>,
+}
+
+/// How much NV memory the TPM has left, as far as it reports. TPMs do not report their free NV
+/// memory in bytes, so these are the closest properties they have.
+#[derive(Clone, Copy, Debug)]
+pub struct NvBudget {
+    /// Largest NV index the TPM allows, in bytes. A smaller index may still fail with
+    /// `NvSpaceFull` when other indices used up the memory.
+    pub available_bytes: u32,
+    /// The TPM's estimate of how many more handles fit in its NV memory, which persistent objects
+    /// and NV indices share. When 0, `init` is likely to fail with `NvSpaceFull`.
+    pub max_handles: u32,
+}
+
+/// Kind of TPM, as inferred from its manufacturer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TpmType {
+    /// Discrete TPM chip, such as from Infineon, Nuvoton, or STMicroelectronics.
+    Discrete,
+    /// Firmware TPM running in the processor, such as Intel PTT or AMD fTPM.
+    Firmware,
+    /// Software TPM, such as a simulator or the TPM of a virtual machine.
+    Virtual,
+    Unknown,
+}
+
+impl TpmType {
+    fn from_manufacturer(manufacturer: &str) -> Self {
+        match manufacturer {
+            "INTC" | "AMD" | "QCOM" | "MSFT" => Self::Firmware,
+            "IFX" | "NTC" | "NTZ" | "NSM" | "STM" | "ATML" | "SMSC" | "BRCM" | "GOOG" => Self::Discrete,
+            "IBM" | "SW" => Self::Virtual,
+            _ => Self::Unknown,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Discrete => "discrete",
+            Self::Firmware => "firmware",
+            Self::Virtual => "virtual",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl FromStr for TpmType {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "discrete" => Ok(Self::Discrete),
+            "firmware" => Ok(Self::Firmware),
+            "virtual" => Ok(Self::Virtual),
+            "unknown" => Ok(Self::Unknown),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Hash algorithm of a PCR bank.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashAlg {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+    Sm3_256,
+    /// An algorithm by its TPM algorithm ID.
+    Other(u16),
+}
+
+impl HashAlg {
+    /// The algorithm with the given TPM algorithm ID, such as `TPM2_ALG_SHA256`.
+    pub fn from_id(id: u16) -> Self {
+        use tss_esapi::constants::tss::*;
+        match id {
+            TPM2_ALG_SHA1 => Self::Sha1,
+            TPM2_ALG_SHA256 => Self::Sha256,
+            TPM2_ALG_SHA384 => Self::Sha384,
+            TPM2_ALG_SHA512 => Self::Sha512,
+            TPM2_ALG_SM3_256 => Self::Sm3_256,
+            _ => Self::Other(id),
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        use tss_esapi::constants::tss::*;
+        match self {
+            Self::Sha1 => TPM2_ALG_SHA1,
+            Self::Sha256 => TPM2_ALG_SHA256,
+            Self::Sha384 => TPM2_ALG_SHA384,
+            Self::Sha512 => TPM2_ALG_SHA512,
+            Self::Sm3_256 => TPM2_ALG_SM3_256,
+            Self::Other(id) => *id,
+        }
+    }
+
+    /// The `Tpm2Totp::BANK_*` bit selecting this bank, if tpm2-totp supports it.
+    pub fn bank(&self) -> Option<u32> {
+        match self {
+            Self::Sha1 => Some(Tpm2Totp::BANK_SHA1),
+            Self::Sha256 => Some(Tpm2Totp::BANK_SHA256),
+            Self::Sha384 => Some(Tpm2Totp::BANK_SHA384),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Self::Sha1 => "SHA1".to_string(),
+            Self::Sha256 => "SHA256".to_string(),
+            Self::Sha384 => "SHA384".to_string(),
+            Self::Sha512 => "SHA512".to_string(),
+            Self::Sm3_256 => "SM3-256".to_string(),
+            Self::Other(id) => format!("0x{:04x}", id),
+        }
+    }
+}
+
+/// Step of `Tpm2Totp::init` or `Tpm2Totp::commit_reseal`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Phase {
+    /// Reading the key blob from its NV index.
+    LoadKey,
+    /// Generating the secret and sealing it to the PCRs.
+    GenerateKey,
+    /// Sealing the secret to the new PCRs.
+    Reseal,
+    /// Undefining the NV index holding the old key blob.
+    DeleteKey,
+    /// Writing the key blob to its NV index.
+    StoreKey,
+}
+
+impl Phase {
+    pub fn from_u32(phase: u32) -> Option<Self> {
+        match phase {
+            0 => Some(Self::LoadKey),
+            1 => Some(Self::GenerateKey),
+            2 => Some(Self::Reseal),
+            3 => Some(Self::DeleteKey),
+            4 => Some(Self::StoreKey),
+            _ => None,
+        }
+    }
+
+    pub fn as_u32(&self) -> u32 {
+        match self {
+            Self::LoadKey => 0,
+            Self::GenerateKey => 1,
+            Self::Reseal => 2,
+            Self::DeleteKey => 3,
+            Self::StoreKey => 4,
+        }
+    }
+}
+
+/// Sent to the sender set with `Tpm2Totp::set_progress` as each phase starts.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    pub phase: Phase,
+    /// Number of the phase, starting at 1.
+    pub step: u32,
+    /// Number of phases of the operation.
+    pub steps: u32,
+}
+
+/// A reseal returned by `Tpm2Totp::prepare_reseal`, which must be passed back to
+/// `Tpm2Totp::commit_reseal` to replace the stored key.
+#[derive(Debug)]
+pub struct ResealPlan {
+    /// Mask of PCRs the secret is currently sealed to.
+    pub current_pcrs: u32,
+    /// Mask of PCR banks the secret is currently sealed to.
+    pub current_banks: u32,
+    /// Mask of PCRs the secret will be sealed to.
+    pub target_pcrs: u32,
+    /// Mask of PCR banks the secret will be sealed to.
+    pub target_banks: u32,
+    nv_index: u32,
+}
 
 impl TotpError {
     fn from_rc(rc: libc::c_int) -> Self {
@@ -62,7 +788,20 @@ impl TotpError {
         const RC_SYSTEM_STATE_CHANGED: libc::c_int = (TPM2_RC_POLICY_FAIL | TPM2_RC_9) as _;
         const RC_WRONG_PASSWORD: libc::c_int = (TPM2_RC_AUTH_FAIL | TPM2_RC_9) as _;
         const RC_LOCKOUT: libc::c_int = TPM2_RC_LOCKOUT as _;
-        println!("{:x}", rc);
+        const RC_NV_SPACE_FULL: libc::c_int = TPM2_RC_NV_SPACE as _;
+        const RC_NOT_STARTED: libc::c_int = TPM2_RC_INITIALIZE as _;
+        const RC_RETRY: libc::c_int = TPM2_RC_RETRY as _;
+        const RC_YIELDED: libc::c_int = TPM2_RC_YIELDED as _;
+        const RC_TESTING: libc::c_int = TPM2_RC_TESTING as _;
+        // Other TPM applications holding transient objects or sessions, which the resource
+        // manager could not swap out
+        const RC_OBJECT_MEMORY: libc::c_int = TPM2_RC_OBJECT_MEMORY as _;
+        const RC_SESSION_MEMORY: libc::c_int = TPM2_RC_SESSION_MEMORY as _;
+        // Only reached once `retry` gave up
+        match rc & 0xFFFF {
+            RC_RETRY | RC_YIELDED | RC_TESTING | RC_OBJECT_MEMORY | RC_SESSION_MEMORY => return Self::TpmBusy,
+            _ => (),
+        }
         match rc {
             RC_NO_PASSWORD_PROVIDED => Self::NoPasswordProvided,
             RC_SECRET_HAS_NO_PASSWORD => Self::SecretHasNoPassword,
@@ -71,205 +810,1318 @@ impl TotpError {
             RC_SYSTEM_STATE_CHANGED => Self::SystemStateChanged,
             RC_WRONG_PASSWORD => Self::WrongPassword,
             RC_LOCKOUT => Self::Lockout,
-            _ => Self::Other(format!("unknown (0x{:x}", rc)),
+            RC_NV_SPACE_FULL => Self::NvSpaceFull,
+            RC_NOT_STARTED => Self::NotStarted,
+            _ => Self::Unknown(rc as u32),
+        }
+    }
+
+    /// The raw return code, if this error did not map to a more specific variant.
+    pub fn rc(&self) -> Option<u32> {
+        match self {
+            Self::Unknown(rc) => Some(*rc),
+            _ => None,
+        }
+    }
+
+    /// Whether the connection to the TPM failed, rather than the TPM, after which the TCTI context
+    /// is unusable until `Tpm2Totp::reconnect` opens a new one.
+    pub fn is_transport(&self) -> bool {
+        use tss_esapi::constants::tss::*;
+        match self.rc() {
+            Some(rc) => rc & 0xFF_0000 == TSS2_TCTI_RC_LAYER && matches!(
+                rc & 0xFFFF,
+                TSS2_BASE_RC_IO_ERROR | TSS2_BASE_RC_NO_CONNECTION | TSS2_BASE_RC_BAD_SEQUENCE
+            ),
+            None => false,
         }
     }
 }
 
+// Key blob loaded from NVRAM, reused by `show` for the rest of the window it was loaded in
+struct KeyCache {
+    window: u64,
+    blob: Vec<u8>,
+}
+
 pub struct Tpm2Totp {
-    context: TctiContext,
+    // Only missing once `reconnect` failed to open a new TCTI context
+    context: Option<TctiContext>,
+    key_cache: Option<KeyCache>,
+    pcrs: u32,
+    banks: u32,
+    nv_index: u32,
+    journal: Option<PathBuf>,
+    autodiscover: bool,
+    // NV index that autodiscovery last found the secret at, until taken
+    discovered_index: Option<u32>,
+    // Error of the library that `show` fell back to the tpm2-totp command after, until taken
+    fallback_error: Option<TotpError>,
+    timings: Timings,
+    progress: Option<mpsc::Sender<Progress>>,
 }
 
+// The TCTI context and the ESAPI contexts created from it are only ever used through `&mut self`,
+// and the TSS libraries keep no thread local state, so moving an instance to another thread is
+// sound. It is not `Sync`: the TSS contexts must not be used from two threads at once, so sharing
+// needs a `Mutex`.
+unsafe impl Send for Tpm2Totp {}
+
 impl Tpm2Totp {
     // We have chosen by default to measure PCR 0, 2, and 7. This allows for changes to firmware,
     // option roms, or the secure boot state to be detected. Changes to the OS are intended to
     // be verified with secure boot.
-    const PCRS: u32 = (1 << 0) | (1 << 2) | (1 << 7);
+    pub const PCRS: u32 = (1 << 0) | (1 << 2) | (1 << 7);
+
+    /// Bit selecting the SHA1 PCR bank.
+    pub const BANK_SHA1: u32 = 1 << 0;
+    /// Bit selecting the SHA256 PCR bank.
+    pub const BANK_SHA256: u32 = 1 << 1;
+    /// Bit selecting the SHA384 PCR bank.
+    pub const BANK_SHA384: u32 = 1 << 2;
+
+    /// PCRs that change with benign events, so that a secret bound to them often needs resealing:
+    /// PCR 1 with firmware settings such as the boot order, and PCR 4 with boot loader updates.
+    pub const FRAGILE_PCRS: u32 = (1 << 1) | (1 << 4);
 
     // Choose bank 0 and 1, which are SHA1 and SHA256
-    const BANKS: u32 = (1 << 0) | (1 << 1);
+    pub const BANKS: u32 = Self::BANK_SHA1 | Self::BANK_SHA256;
 
     // Use the same default NVRAM index as tpm2-totp command line
     const NVRAM_INDEX: u32 = 0x018094AF;
 
+    /// Environment variables selecting the TCTI, checked in order. Accepted values are those of
+    /// the tpm2-tools `--tcti` option, such as `device:/dev/tpmrm0`, `mssim:host=localhost,port=2321`,
+    /// or `swtpm:path=/tmp/swtpm.sock`. When none are set, the default TPM device is used.
+    pub const TCTI_VARS: [&'static str; 2] = ["POPSEC_TCTI", "TPM2TOOLS_TCTI"];
+
+    fn tcti() -> Result<TctiNameConf, TotpError> {
+        for var in Self::TCTI_VARS.iter() {
+            if let Ok(value) = env::var(var) {
+                return TctiNameConf::from_str(&value).map_err(|err| TotpError::Other(format!(
+                    "tpm2-totp: invalid TCTI {:?} in {}: {}", value, var, err
+                )));
+            }
+        }
+        Ok(TctiNameConf::Device(DeviceConfig::default()))
+    }
+
     pub fn new() -> Result<Self, TotpError> {
-        let context = TctiContext::initialize(TctiNameConf::Device(
-            DeviceConfig::default()
-        )).map_err(|err| TotpError::Other(format!(
+        let context = TctiContext::initialize(Self::tcti()?).map_err(|err| TotpError::Other(format!(
             "tpm2-totp: failed to initialize TCTI context: {}", err
         )))?;
         Ok(Self {
-            context
+            context: Some(context),
+            key_cache: None,
+            pcrs: Self::PCRS,
+            banks: Self::BANKS,
+            nv_index: Self::NVRAM_INDEX,
+            journal: None,
+            autodiscover: false,
+            discovered_index: None,
+            fallback_error: None,
+            timings: Timings::default(),
+            progress: None,
         })
     }
 
+    /// Set the mask of PCRs that `init` and `commit_reseal` bind the secret to, where bit N selects
+    /// PCR N.
+    pub fn with_pcrs(mut self, pcrs: u32) -> Self {
+        self.set_pcrs(pcrs);
+        self
+    }
+
+    /// Like `with_pcrs`, for an existing instance.
+    pub fn set_pcrs(&mut self, pcrs: u32) {
+        self.pcrs = pcrs;
+    }
+
+    /// Advice to show before `init` or `commit_reseal` when the configured PCRs include any of
+    /// `FRAGILE_PCRS`. Binding to them is allowed, so this is not an error.
+    pub fn pcr_advisory(&self) -> Option<String> {
+        let fragile: Vec<String> = (0..32)
+            .filter(|pcr| self.pcrs & Self::FRAGILE_PCRS & (1 << pcr) != 0)
+            .map(|pcr: u32| pcr.to_string())
+            .collect();
+        if fragile.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "PCR {} change with benign updates or settings, expect to reseal the secret often",
+            fragile.join(", ")
+        ))
+    }
+
+    /// Set the mask of PCR banks that `init` and `commit_reseal` bind the secret to, made of the
+    /// `BANK_*` bits.
+    pub fn with_banks(mut self, banks: u32) -> Self {
+        self.banks = banks;
+        self
+    }
+
+    /// Use only the SHA256 bank, and the SHA384 bank if the TPM has it allocated, as SHA1 is not
+    /// allowed on systems in FIPS mode.
+    pub fn fips_mode(mut self) -> Self {
+        self.banks = Self::BANK_SHA256;
+        if let Ok(banks) = self.active_banks() {
+            if banks.contains(&HashAlg::Sha384) {
+                self.banks |= Self::BANK_SHA384;
+            }
+        }
+        self
+    }
+
+    /// Store the secret at `nv_index` instead of the default index of the tpm2-totp command.
+    pub fn with_nv_index(mut self, nv_index: u32) -> Self {
+        self.nv_index = nv_index;
+        self
+    }
+
+    /// The NV index the secret is stored at.
+    pub fn nv_index(&self) -> u32 {
+        self.nv_index
+    }
+
+    /// Record the resealed key in a journal file while `commit_reseal` replaces the NV index, so
+    /// that `complete_journal` can restore it if the reseal is interrupted.
+    pub fn with_journal<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.journal = Some(path.into());
+        self
+    }
+
+    /// When no secret is found at the configured NV index, have `show` fall back to the first
+    /// other NV index holding a tpm2-totp secret, such as one enrolled by another tool.
+    pub fn with_autodiscover(mut self, autodiscover: bool) -> Self {
+        self.autodiscover = autodiscover;
+        self
+    }
+
+    /// Report the phases of `init` and `commit_reseal` to `sender` as they start, or stop
+    /// reporting them with `None`. Nothing is sent once the receiver is dropped.
+    pub fn set_progress(&mut self, sender: Option<mpsc::Sender<Progress>>) {
+        self.progress = sender;
+    }
+
+    fn report(&self, phase: Phase, step: u32, steps: u32) {
+        if let Some(sender) = &self.progress {
+            let _ = sender.send(Progress { phase, step, steps });
+        }
+    }
+
+    fn tcti_context(&self) -> Result<*mut TSS2_TCTI_CONTEXT, TotpError> {
+        self.context.as_ref()
+            .map(|context| context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT)
+            .ok_or_else(|| TotpError::Other("tpm2-totp: not connected to the TPM".to_string()))
+    }
+
+    /// Close the TCTI context and open a new one, for when the connection to the TPM broke, such as
+    /// after the TPM driver was reset. Until a new context is opened, every call fails.
+    pub fn reconnect(&mut self) -> Result<(), TotpError> {
+        self.invalidate_cache();
+        // The TPM device can only be opened once, so the old context is closed first
+        self.context = None;
+        let context = TctiContext::initialize(Self::tcti()?).map_err(|err| TotpError::Other(format!(
+            "tpm2-totp: failed to initialize TCTI context: {}", err
+        )))?;
+        self.context = Some(context);
+        Ok(())
+    }
+
+    fn esys(&mut self) -> Result<EsysContext, TotpError> {
+        let tcti = self.tcti_context()?;
+        let mut esys = EsysContext(ptr::null_mut());
+        let rc = unsafe {
+            Esys_Initialize(
+                &mut esys.0,
+                tcti as *mut tss_esapi::tss2_esys::TSS2_TCTI_CONTEXT,
+                ptr::null_mut()
+            )
+        };
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc as libc::c_int));
+        }
+        Ok(esys)
+    }
+
+    pub fn clock(&mut self) -> Result<TpmClock, TotpError> {
+        let esys = self.esys()?;
+        unsafe {
+            let mut time_info: AutoFree<TPMS_TIME_INFO> = AutoFree(ptr::null_mut());
+            let rc = retry(|| Esys_ReadClock(
+                esys.0,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                &mut time_info.0
+            ));
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+
+            let info = &*time_info.0;
+            Ok(TpmClock {
+                time: info.time,
+                clock: info.clockInfo.clock,
+                reset_count: info.clockInfo.resetCount,
+                restart_count: info.clockInfo.restartCount,
+                safe: info.clockInfo.safe != 0,
+            })
+        }
+    }
+
+    /// Whether the firmware measured the boot, judged by PCR 0 having been extended. Without
+    /// measurements, the PCRs do not change when the firmware does, and binding the secret to them
+    /// protects nothing.
+    pub fn measured_boot_active(&mut self) -> Result<bool, TotpError> {
+        let esys = self.esys()?;
+        unsafe {
+            let selection = pcr_selection(1 << 0);
+            let mut update_counter = 0;
+            let mut selection_out: AutoFree<TPML_PCR_SELECTION> = AutoFree(ptr::null_mut());
+            let mut values: AutoFree<TPML_DIGEST> = AutoFree(ptr::null_mut());
+            let rc = retry(|| Esys_PCR_Read(
+                esys.0,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                &selection,
+                &mut update_counter,
+                &mut selection_out.0,
+                &mut values.0
+            ));
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+
+            let values = &*values.0;
+            Ok(values.digests[..values.count as usize].iter().any(|digest| {
+                digest.buffer[..digest.size as usize].iter().any(|&x| x != 0)
+            }))
+        }
+    }
+
+    fn tpm_property(&mut self, property: u32) -> Result<u32, TotpError> {
+        use tss_esapi::constants::tss::*;
+        let esys = self.esys()?;
+        unsafe {
+            let mut more_data: TPMI_YES_NO = 0;
+            let mut capability_data: AutoFree<TPMS_CAPABILITY_DATA> = AutoFree(ptr::null_mut());
+            let rc = retry(|| Esys_GetCapability(
+                esys.0,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                TPM2_CAP_TPM_PROPERTIES,
+                property,
+                1,
+                &mut more_data,
+                &mut capability_data.0
+            ));
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+
+            // The next property is returned when the requested one is not implemented
+            let properties = &(*capability_data.0).data.tpmProperties;
+            Ok(properties.tpmProperty[..properties.count as usize].iter()
+                .find(|x| x.property == property)
+                .map_or(0, |x| x.value))
+        }
+    }
+
+    // Vendor ID, which is up to four ASCII characters padded with spaces or NUL bytes
+    fn manufacturer(&mut self) -> Result<String, TotpError> {
+        use tss_esapi::constants::tss::*;
+        Ok(self.tpm_property(TPM2_PT_MANUFACTURER)?.to_be_bytes().iter()
+            .filter(|&&x| x.is_ascii_graphic())
+            .map(|&x| x as char)
+            .collect())
+    }
+
+    /// Query how much NV memory is left, to warn before `init` fails with `NvSpaceFull`.
+    pub fn nv_budget(&mut self) -> Result<NvBudget, TotpError> {
+        use tss_esapi::constants::tss::*;
+        Ok(NvBudget {
+            available_bytes: self.tpm_property(TPM2_PT_NV_INDEX_MAX)?,
+            max_handles: self.tpm_property(TPM2_PT_HR_PERSISTENT_AVAIL)?,
+        })
+    }
+
+    /// Whether the TPM is a discrete chip, part of the processor firmware, or virtual, inferred from
+    /// the manufacturer. Unrecognized manufacturers give `TpmType::Unknown`.
+    pub fn tpm_type(&mut self) -> Result<TpmType, TotpError> {
+        self.manufacturer().map(|manufacturer| TpmType::from_manufacturer(&manufacturer))
+    }
+
+    /// Collect information about the TPM and the TOTP secret, for bug reports.
+    pub fn diagnostics(&mut self) -> Result<Diagnostics, TotpError> {
+        use tss_esapi::constants::tss::*;
+        let manufacturer = self.manufacturer()?;
+        let firmware_version = u64::from(self.tpm_property(TPM2_PT_FIRMWARE_VERSION_1)?) << 32
+            | u64::from(self.tpm_property(TPM2_PT_FIRMWARE_VERSION_2)?);
+        let (enrolled, pcrs, banks) = match self.cached_key() {
+            Ok(key) => {
+                let info = KeyInfo::parse(key)?;
+                (true, info.pcrs, info.banks)
+            },
+            Err(TotpError::SecretNotFound) => (false, 0, 0),
+            Err(err) => return Err(err),
+        };
+        Ok(Diagnostics {
+            manufacturer,
+            firmware_version,
+            enrolled,
+            pcrs,
+            banks,
+            clock: self.clock()?,
+            lockout_counter: self.tpm_property(TPM2_PT_LOCKOUT_COUNTER)?,
+            max_auth_fail: self.tpm_property(TPM2_PT_MAX_AUTH_FAIL)?,
+            lockout_interval: self.tpm_property(TPM2_PT_LOCKOUT_INTERVAL)?,
+            lockout_recovery: self.tpm_property(TPM2_PT_LOCKOUT_RECOVERY)?,
+            in_lockout: self.tpm_property(TPM2_PT_PERMANENT)? & TPMA_PERMANENT_INLOCKOUT != 0,
+            timings: self.timings,
+        })
+    }
+
+    fn nv_indices(&mut self) -> Result<Vec<u32>, TotpError> {
+        use tss_esapi::constants::tss::*;
+        let esys = self.esys()?;
+        let mut indices = Vec::new();
+        let mut property = TPM2_NV_INDEX_FIRST;
+        loop {
+            unsafe {
+                let mut more_data: TPMI_YES_NO = 0;
+                let mut capability_data: AutoFree<TPMS_CAPABILITY_DATA> = AutoFree(ptr::null_mut());
+                let rc = retry(|| Esys_GetCapability(
+                    esys.0,
+                    ESYS_TR_NONE,
+                    ESYS_TR_NONE,
+                    ESYS_TR_NONE,
+                    TPM2_CAP_HANDLES,
+                    property,
+                    64,
+                    &mut more_data,
+                    &mut capability_data.0
+                ));
+                if rc != 0 {
+                    return Err(TotpError::from_rc(rc as libc::c_int));
+                }
+
+                let handles = &(*capability_data.0).data.handles;
+                let handles = &handles.handle[..handles.count as usize];
+                indices.extend_from_slice(handles);
+
+                match handles.last() {
+                    Some(last) if more_data != 0 => property = last + 1,
+                    _ => break,
+                }
+            }
+        }
+        Ok(indices)
+    }
+
+    /// The PCR banks the TPM has allocated, which are the only ones a secret can be bound to.
+    pub fn active_banks(&mut self) -> Result<Vec<HashAlg>, TotpError> {
+        use tss_esapi::constants::tss::*;
+        let esys = self.esys()?;
+        unsafe {
+            let mut more_data: TPMI_YES_NO = 0;
+            let mut capability_data: AutoFree<TPMS_CAPABILITY_DATA> = AutoFree(ptr::null_mut());
+            let rc = retry(|| Esys_GetCapability(
+                esys.0,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                TPM2_CAP_PCRS,
+                0,
+                1,
+                &mut more_data,
+                &mut capability_data.0
+            ));
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+
+            // Banks can be listed without any PCRs selected, which leaves them unallocated
+            let assigned = &(*capability_data.0).data.assignedPCR;
+            Ok(assigned.pcrSelections[..assigned.count as usize].iter()
+                .filter(|selection| {
+                    selection.pcrSelect[..selection.sizeofSelect as usize].iter().any(|&x| x != 0)
+                })
+                .map(|selection| HashAlg::from_id(selection.hash))
+                .collect())
+        }
+    }
+
+    /// List the NV indices that hold a tpm2-totp secret.
+    pub fn list_slots(&mut self) -> Result<Vec<u32>, TotpError> {
+        let mut slots = Vec::new();
+        for nv_index in self.nv_indices()? {
+            if unsafe { self.load_key(nv_index) }.is_ok() {
+                slots.push(nv_index);
+            }
+        }
+        Ok(slots)
+    }
+
+    unsafe fn load_key(&mut self, nv_index: u32) -> Result<(AutoFree<u8>, size_t), TotpError> {
+        let tcti = self.tcti_context()?;
+        let mut key_blob = AutoFree(ptr::null_mut());
+        let mut key_blob_size = 0;
+        let rc = retry(|| tpm2totp_loadKey_nv(
+            nv_index,
+            tcti,
+            &mut key_blob.0,
+            &mut key_blob_size
+        ));
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc));
+        }
+
+        // Catch foreign or truncated data early, before it makes tpm2-totp fail cryptically
+        let max_size = 12 + mem::size_of::<TPM2B_PUBLIC>() + mem::size_of::<TPM2B_PRIVATE>();
+        if (key_blob_size as usize) > max_size {
+            return Err(TotpError::CorruptBlob);
+        }
+        let blob = slice::from_raw_parts(key_blob.0, key_blob_size as usize);
+        if KeyInfo::parse(blob).is_err() {
+            return Err(TotpError::CorruptBlob);
+        }
+
+        Ok((key_blob, key_blob_size))
+    }
+
+    // Read the public area and name of the NV index holding the secret
+    unsafe fn nv_public(&self, esys: &EsysContext) -> Result<(AutoFree<TPM2B_NV_PUBLIC>, AutoFree<TPM2B_NAME>), TotpError> {
+        Self::nv_public_at(esys, Self::nv_handle(esys, self.nv_index)?)
+    }
+
+    unsafe fn nv_handle(esys: &EsysContext, nv_index: u32) -> Result<ESYS_TR, TotpError> {
+        let mut nv_handle = ESYS_TR_NONE;
+        let rc = retry(|| Esys_TR_FromTPMPublic(
+            esys.0,
+            nv_index,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            &mut nv_handle
+        ));
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc as libc::c_int));
+        }
+        Ok(nv_handle)
+    }
+
+    unsafe fn nv_public_at(esys: &EsysContext, nv_handle: ESYS_TR) -> Result<(AutoFree<TPM2B_NV_PUBLIC>, AutoFree<TPM2B_NAME>), TotpError> {
+        let mut nv_public = AutoFree(ptr::null_mut());
+        let mut nv_name = AutoFree(ptr::null_mut());
+        let rc = retry(|| Esys_NV_ReadPublic(
+            esys.0,
+            nv_handle,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            &mut nv_public.0,
+            &mut nv_name.0
+        ));
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc as libc::c_int));
+        }
+        Ok((nv_public, nv_name))
+    }
+
+    // Tell apart an NV index holding a tpm2-totp secret from one used by another application, so
+    // that `init` does not report unrelated data as an existing secret
+    fn check_index_free(&mut self) -> Result<(), TotpError> {
+        let data_size = {
+            let esys = self.esys()?;
+            match unsafe { self.nv_public(&esys) } {
+                Ok((nv_public, _nv_name)) => unsafe { (*nv_public.0).nvPublic.dataSize },
+                // The index is not defined
+                Err(TotpError::SecretNotFound) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        };
+        // A key blob holds at least its version, PCR and bank masks
+        if data_size < 12 {
+            return Err(TotpError::IndexInUse);
+        }
+        match unsafe { self.load_key(self.nv_index) } {
+            Ok(_) => Err(TotpError::SecretAlreadyExists),
+            Err(_) => Err(TotpError::IndexInUse),
+        }
+    }
+
+    // Read the whole data of an NV index, authorizing with its empty auth value, in chunks of at
+    // most `chunk` bytes
+    unsafe fn nv_read(esys: &EsysContext, nv_index: u32, chunk: u16) -> Result<Vec<u8>, TotpError> {
+        let nv_handle = Self::nv_handle(esys, nv_index)?;
+        let (nv_public, _nv_name) = Self::nv_public_at(esys, nv_handle)?;
+        let size = (*nv_public.0).nvPublic.dataSize;
+
+        let mut data = Vec::with_capacity(size as usize);
+        while data.len() < size as usize {
+            let offset = data.len() as u16;
+            let mut buffer = AutoFree(ptr::null_mut());
+            let rc = retry(|| Esys_NV_Read(
+                esys.0,
+                nv_handle,
+                nv_handle,
+                ESYS_TR_PASSWORD,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                chunk.min(size - offset),
+                offset,
+                &mut buffer.0
+            ));
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+            let buffer = &*buffer.0;
+            if buffer.size == 0 {
+                return Err(TotpError::Other(format!(
+                    "tpm2-totp: NV index 0x{:08x} returned no data", nv_index
+                )));
+            }
+            data.extend_from_slice(&buffer.buffer[..buffer.size as usize]);
+        }
+        Ok(data)
+    }
+
+    /// Read the endorsement key certificate, in DER format, which the TPM manufacturer stores to
+    /// prove that the TPM is genuine. The RSA certificate is preferred over the ECC one. Firmware
+    /// and virtual TPMs often have neither.
+    pub fn ek_certificate(&mut self) -> Result<Vec<u8>, TotpError> {
+        use tss_esapi::constants::tss::*;
+        // Some TPMs report no limit, in which case the size every TPM supports is used
+        let chunk = match self.tpm_property(TPM2_PT_NV_BUFFER_MAX)? {
+            0 => 512,
+            max => max.min(u32::from(u16::MAX)) as u16,
+        };
+        let esys = self.esys()?;
+        let mut result = Err(TotpError::Other("tpm2-totp: no EK certificate".to_string()));
+        for &nv_index in EK_CERT_INDICES.iter() {
+            match unsafe { Self::nv_read(&esys, nv_index, chunk) } {
+                Ok(cert) => return Ok(cert),
+                // The index of a key without a certificate is not defined
+                Err(TotpError::SecretNotFound) => (),
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
+
+    /// Whether the NV index holding the secret is write locked, which prevents resealing.
+    pub fn nv_locked(&mut self) -> Result<bool, TotpError> {
+        use tss_esapi::constants::tss::*;
+        let esys = self.esys()?;
+        unsafe {
+            let (nv_public, _nv_name) = self.nv_public(&esys)?;
+            Ok((*nv_public.0).nvPublic.attributes & TPMA_NV_WRITELOCKED != 0)
+        }
+    }
+
+    // Create the attestation key used by `attest`. As a primary key created from a fixed template
+    // in the endorsement hierarchy, it is the same key every time for a given TPM.
+    unsafe fn attestation_key(esys: &EsysContext) -> Result<(ESYS_TR, AutoFree<TPM2B_PUBLIC>), TotpError> {
+        use tss_esapi::constants::tss::*;
+
+        let in_sensitive: TPM2B_SENSITIVE_CREATE = mem::zeroed();
+        let mut in_public: TPM2B_PUBLIC = mem::zeroed();
+        in_public.publicArea.type_ = TPM2_ALG_ECC;
+        in_public.publicArea.nameAlg = TPM2_ALG_SHA256;
+        in_public.publicArea.objectAttributes = TPMA_OBJECT_FIXEDTPM
+            | TPMA_OBJECT_FIXEDPARENT
+            | TPMA_OBJECT_SENSITIVEDATAORIGIN
+            | TPMA_OBJECT_USERWITHAUTH
+            | TPMA_OBJECT_RESTRICTED
+            | TPMA_OBJECT_SIGN_ENCRYPT;
+        let ecc = &mut in_public.publicArea.parameters.eccDetail;
+        ecc.symmetric.algorithm = TPM2_ALG_NULL;
+        ecc.scheme.scheme = TPM2_ALG_ECDSA;
+        ecc.scheme.details.ecdsa.hashAlg = TPM2_ALG_SHA256;
+        ecc.curveID = TPM2_ECC_NIST_P256;
+        ecc.kdf.scheme = TPM2_ALG_NULL;
+        let outside_info: TPM2B_DATA = mem::zeroed();
+        let creation_pcr: TPML_PCR_SELECTION = mem::zeroed();
+
+        let mut handle = ESYS_TR_NONE;
+        let mut out_public = AutoFree(ptr::null_mut());
+        let mut creation_data = AutoFree(ptr::null_mut());
+        let mut creation_hash = AutoFree(ptr::null_mut());
+        let mut creation_ticket = AutoFree(ptr::null_mut());
+        let rc = retry(|| Esys_CreatePrimary(
+            esys.0,
+            ESYS_TR_RH_ENDORSEMENT,
+            ESYS_TR_PASSWORD,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            &in_sensitive,
+            &in_public,
+            &outside_info,
+            &creation_pcr,
+            &mut handle,
+            &mut out_public.0,
+            &mut creation_data.0,
+            &mut creation_hash.0,
+            &mut creation_ticket.0
+        ));
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc as libc::c_int));
+        }
+        Ok((handle, out_public))
+    }
+
+    /// Quote the PCRs the secret is sealed to, with the name of the NV index holding the secret as
+    /// qualifying data. Returns the TPM marshaled attestation structure, followed by its signature,
+    /// followed by the public area of the attestation key that signed it.
+    pub fn attest(&mut self) -> Result<Vec<u8>, TotpError> {
+        use tss_esapi::constants::tss::*;
+        // The PCRs and banks the secret was sealed to, which differ from the configured ones until
+        // it is resealed
+        let info = unsafe {
+            let (key_blob, key_blob_size) = self.load_key(self.nv_index)?;
+            KeyInfo::parse(slice::from_raw_parts(key_blob.0, key_blob_size as usize))?
+        };
+        let esys = self.esys()?;
+        unsafe {
+            let (_nv_public, nv_name) = self.nv_public(&esys)?;
+
+            let mut qualifying_data: TPM2B_DATA = mem::zeroed();
+            let name = &*nv_name.0;
+            let name_size = (name.size as usize).min(qualifying_data.buffer.len());
+            qualifying_data.buffer[..name_size].copy_from_slice(&name.name[..name_size]);
+            qualifying_data.size = name_size as u16;
+
+            let (ak_handle, ak_public) = Self::attestation_key(&esys)?;
+
+            let mut scheme: TPMT_SIG_SCHEME = mem::zeroed();
+            scheme.scheme = TPM2_ALG_NULL;
+            let selection = bank_pcr_selection(info.pcrs, info.banks);
+            let mut quoted: AutoFree<TPM2B_ATTEST> = AutoFree(ptr::null_mut());
+            let mut signature: AutoFree<TPMT_SIGNATURE> = AutoFree(ptr::null_mut());
+            let mut rc = retry(|| Esys_Quote(
+                esys.0,
+                ak_handle,
+                ESYS_TR_PASSWORD,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                &qualifying_data,
+                &scheme,
+                &selection,
+                &mut quoted.0,
+                &mut signature.0
+            ));
+            Esys_FlushContext(esys.0, ak_handle);
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+
+            let mut buffer = vec![
+                0;
+                mem::size_of::<TPM2B_ATTEST>()
+                    + mem::size_of::<TPMT_SIGNATURE>()
+                    + mem::size_of::<TPM2B_PUBLIC>()
+            ];
+            let mut offset = 0;
+            rc = Tss2_MU_TPM2B_ATTEST_Marshal(quoted.0, buffer.as_mut_ptr(), buffer.len() as _, &mut offset);
+            if rc == 0 {
+                rc = Tss2_MU_TPMT_SIGNATURE_Marshal(signature.0, buffer.as_mut_ptr(), buffer.len() as _, &mut offset);
+            }
+            if rc == 0 {
+                rc = Tss2_MU_TPM2B_PUBLIC_Marshal(ak_public.0, buffer.as_mut_ptr(), buffer.len() as _, &mut offset);
+            }
+            if rc != 0 {
+                return Err(TotpError::from_rc(rc as libc::c_int));
+            }
+            buffer.truncate(offset as usize);
+            Ok(buffer)
+        }
+    }
+
+    /// Drop the key blob cached by `show`, so that the next call loads it from NVRAM again.
+    pub fn invalidate_cache(&mut self) {
+        self.key_cache = None;
+    }
+
+    /// Delete the secret from the NV index. Codes cannot be shown again until `init` sets up a
+    /// new secret.
     pub fn clean(&mut self) -> Result<(), TotpError> {
-        unimplemented!();
+        self.invalidate_cache();
+        let tcti = self.tcti_context()?;
+        let rc = unsafe {
+            retry(|| tpm2totp_deleteKey_nv(
+                self.nv_index,
+                tcti
+            ))
+        };
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc));
+        }
+        Ok(())
+    }
+
+    /// Durations of the last `init`, `commit_reseal`, and `show`.
+    pub fn timings(&self) -> Timings {
+        self.timings
+    }
+
+    /// The NV index that autodiscovery found the secret at, as there was none at the configured
+    /// index, if it did since the last call.
+    pub fn take_discovered_index(&mut self) -> Option<u32> {
+        self.discovered_index.take()
+    }
+
+    /// The error that made `show` fall back to the tpm2-totp command, if it did since the last
+    /// call. Always `None` without the `cli-fallback` feature.
+    pub fn take_fallback_error(&mut self) -> Option<TotpError> {
+        self.fallback_error.take()
     }
 
     pub fn init(&mut self, password: &TotpPass) -> Result<TotpSecret, TotpError> {
+        let start = Instant::now();
+        let result = self.init_ffi(password);
+        self.timings.init = Some(start.elapsed());
+        result
+    }
+
+    fn init_ffi(&mut self, password: &TotpPass) -> Result<TotpSecret, TotpError> {
+        self.invalidate_cache();
+        self.check_index_free()?;
+        let tcti = self.tcti_context()?;
         unsafe {
-            let password_c = CString::new(password.0.as_str()).map_err(|err| {
-                TotpError::Other(format!(
-                    "failed to convert password to C string: {}", err
-                ))
-            })?;
+            let password_c = password.to_cstring()?;
             let mut secret = AutoFree(ptr::null_mut());
             let mut secret_size = 0;
             let mut key_blob = AutoFree(ptr::null_mut());
             let mut key_blob_size = 0;
-            let mut rc = tpm2totp_generateKey(
-                Self::PCRS,
-                Self::BANKS,
+            self.report(Phase::GenerateKey, 1, 2);
+            let mut rc = retry(|| tpm2totp_generateKey(
+                self.pcrs,
+                self.banks,
                 password_c.as_ptr(),
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
+                tcti,
                 &mut secret.0,
                 &mut secret_size,
                 &mut key_blob.0,
                 &mut key_blob_size
-            );
+            ));
             if rc != 0 {
                 return Err(TotpError::from_rc(rc));
             }
 
-            rc = tpm2totp_storeKey_nv(
+            self.report(Phase::StoreKey, 2, 2);
+            rc = retry(|| tpm2totp_storeKey_nv(
                 key_blob.0,
                 key_blob_size,
-                Self::NVRAM_INDEX,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
-            );
+                self.nv_index,
+                tcti,
+            ));
             if rc != 0 {
                 return Err(TotpError::from_rc(rc));
             }
 
             let secret_vec = slice::from_raw_parts(secret.0, secret_size as usize).to_vec();
-            Ok(TotpSecret(secret_vec))
+            ptr::write_bytes(secret.0, 0, secret_size as usize);
+            TotpSecret::new(secret_vec)
         }
     }
 
+    /// Like `init`, with a secret that needs both passwords to be recovered or resealed, as
+    /// combined by `TotpPass::split`.
+    pub fn init_split(&mut self, first: &TotpPass, second: &TotpPass) -> Result<TotpSecret, TotpError> {
+        self.init(&TotpPass::split(first, second))
+    }
+
     pub fn recover(&mut self, password: &TotpPass) -> Result<TotpSecret, TotpError> {
+        let tcti = self.tcti_context()?;
         unsafe {
-            let mut key_blob = AutoFree(ptr::null_mut());
-            let mut key_blob_size = 0;
-            let mut rc = tpm2totp_loadKey_nv(
-                Self::NVRAM_INDEX,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
-                &mut key_blob.0,
-                &mut key_blob_size
-            );
-            if rc != 0 {
-                return Err(TotpError::from_rc(rc));
-            }
+            let (key_blob, key_blob_size) = self.load_key(self.nv_index)?;
 
-            let password_c = CString::new(password.0.as_str()).map_err(|err| {
-                TotpError::Other(format!(
-                    "failed to convert password to C string: {}", err
-                ))
-            })?;
+            let password_c = password.to_cstring()?;
             let mut secret = AutoFree(ptr::null_mut());
             let mut secret_size = 0;
-            rc = tpm2totp_getSecret(
+            let rc = retry(|| tpm2totp_getSecret(
                 key_blob.0,
                 key_blob_size,
                 password_c.as_ptr(),
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
+                tcti,
                 &mut secret.0,
                 &mut secret_size
-            );
+            ));
             if rc != 0 {
                 return Err(TotpError::from_rc(rc));
             }
 
             let secret_vec = slice::from_raw_parts(secret.0, secret_size as usize).to_vec();
-            Ok(TotpSecret(secret_vec))
+            ptr::write_bytes(secret.0, 0, secret_size as usize);
+            TotpSecret::new(secret_vec)
+        }
+    }
+
+    /// Recover a secret set up with `init_split`, which either password alone fails to recover
+    /// with `WrongPassword`.
+    pub fn recover_split(&mut self, first: &TotpPass, second: &TotpPass) -> Result<TotpSecret, TotpError> {
+        self.recover(&TotpPass::split(first, second))
+    }
+
+    /// The codes of the `before` windows preceding the current one, the current window, and the
+    /// `after` windows following it, as pairs of window start time and code. The sealed key can
+    /// only calculate the current code, so the secret is recovered once with the password.
+    pub fn codes_window(
+        &mut self,
+        password: &TotpPass,
+        before: u32,
+        after: u32
+    ) -> Result<Vec<(u64, u32)>, TotpError> {
+        let secret = self.recover(password)?;
+        let current = unix_time() / TOTP_PERIOD;
+        let first = current.saturating_sub(u64::from(before));
+        Ok((first..=current + u64::from(after)).map(|window| {
+            let start = window * TOTP_PERIOD;
+            (start, secret.code_at(start).0 as u32)
+        }).collect())
+    }
+
+    /// Check that the secret can be resealed to the configured PCRs and banks, and describe the
+    /// change for review. Nothing is modified until the plan is passed to `commit_reseal`.
+    pub fn prepare_reseal(&mut self) -> Result<ResealPlan, TotpError> {
+        self.invalidate_cache();
+        if self.nv_locked()? {
+            return Err(TotpError::NvLocked);
         }
+        let info = unsafe {
+            let (key_blob, key_blob_size) = self.load_key(self.nv_index)?;
+            KeyInfo::parse(slice::from_raw_parts(key_blob.0, key_blob_size as usize))?
+        };
+        Ok(ResealPlan {
+            current_pcrs: info.pcrs,
+            current_banks: info.banks,
+            target_pcrs: self.pcrs,
+            target_banks: self.banks,
+            nv_index: self.nv_index,
+        })
+    }
+
+    /// Reseal the secret as described by a plan from `prepare_reseal`, replacing the stored key.
+    pub fn commit_reseal(&mut self, plan: ResealPlan, password: &TotpPass) -> Result<(), TotpError> {
+        let start = Instant::now();
+        let result = self.commit_reseal_ffi(plan, password);
+        self.timings.reseal = Some(start.elapsed());
+        result
     }
 
-    pub fn reseal(&mut self, password: &TotpPass) -> Result<(), TotpError> {
+    fn commit_reseal_ffi(&mut self, plan: ResealPlan, password: &TotpPass) -> Result<(), TotpError> {
+        if plan.nv_index != self.nv_index {
+            return Err(TotpError::Other(format!(
+                "tpm2-totp: reseal plan is for NV index 0x{:08x}, not 0x{:08x}",
+                plan.nv_index,
+                self.nv_index
+            )));
+        }
+        self.invalidate_cache();
+        let tcti = self.tcti_context()?;
         unsafe {
-            let mut key_blob = AutoFree(ptr::null_mut());
-            let mut key_blob_size = 0;
-            let mut rc = tpm2totp_loadKey_nv(
-                Self::NVRAM_INDEX,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
-                &mut key_blob.0,
-                &mut key_blob_size
-            );
-            if rc != 0 {
-                return Err(TotpError::from_rc(rc));
-            }
+            self.report(Phase::LoadKey, 1, 4);
+            let (key_blob, key_blob_size) = self.load_key(self.nv_index)?;
 
-            let password_c = CString::new(password.0.as_str()).map_err(|err| {
-                TotpError::Other(format!(
-                    "failed to convert password to C string: {}", err
-                ))
-            })?;
+            let password_c = password.to_cstring()?;
             let mut new_blob = AutoFree(ptr::null_mut());
             let mut new_blob_size = 0;
-            rc = tpm2totp_reseal(
+            self.report(Phase::Reseal, 2, 4);
+            let mut rc = retry(|| tpm2totp_reseal(
                 key_blob.0,
                 key_blob_size,
                 password_c.as_ptr(),
-                Self::PCRS,
-                Self::BANKS,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
+                plan.target_pcrs,
+                plan.target_banks,
+                tcti,
                 &mut new_blob.0,
                 &mut new_blob_size
-            );
+            ));
             if rc != 0 {
                 return Err(TotpError::from_rc(rc));
             }
 
-            rc = tpm2totp_deleteKey_nv(
-                Self::NVRAM_INDEX,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT
-            );
+            // Between deleting and storing, the secret only exists in the journal
+            let new_blob_slice = slice::from_raw_parts(new_blob.0, new_blob_size as usize);
+            self.write_journal(new_blob_slice)?;
+
+            self.report(Phase::DeleteKey, 3, 4);
+            rc = retry(|| tpm2totp_deleteKey_nv(
+                self.nv_index,
+                tcti
+            ));
             if rc != 0 {
+                self.remove_journal();
                 return Err(TotpError::from_rc(rc));
             }
 
-            rc = tpm2totp_storeKey_nv(
+            self.report(Phase::StoreKey, 4, 4);
+            rc = retry(|| tpm2totp_storeKey_nv(
                 new_blob.0,
                 new_blob_size,
-                Self::NVRAM_INDEX,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT
-            );
+                self.nv_index,
+                tcti
+            ));
             if rc != 0 {
+                // Keep the journal, so the key can still be restored from it
                 return Err(TotpError::from_rc(rc));
             }
 
+            self.remove_journal();
             Ok(())
         }
     }
 
+    // The journal holds the NV index, followed by the key blob
+    fn write_journal(&self, blob: &[u8]) -> Result<(), TotpError> {
+        let path = match &self.journal {
+            Some(some) => some,
+            None => return Ok(()),
+        };
+        let tmp = path.with_extension("tmp");
+        let result = fs::File::create(&tmp).and_then(|mut file| {
+            file.write_all(&self.nv_index.to_le_bytes())?;
+            file.write_all(blob)?;
+            file.sync_all()
+        }).and_then(|()| fs::rename(&tmp, path));
+        result.map_err(|err| TotpError::Other(format!(
+            "tpm2-totp: failed to write reseal journal {}: {}", path.display(), err
+        )))
+    }
+
+    fn remove_journal(&self) {
+        if let Some(path) = &self.journal {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// Finish a reseal that was interrupted, such as by a power loss, by storing the key from the
+    /// journal if its NV index is empty. Returns whether a key was restored.
+    pub fn complete_journal(&mut self) -> Result<bool, TotpError> {
+        let path = match &self.journal {
+            Some(some) => some.clone(),
+            None => return Ok(false),
+        };
+        let data = match fs::read(&path) {
+            Ok(ok) => ok,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(TotpError::Other(format!(
+                "tpm2-totp: failed to read reseal journal {}: {}", path.display(), err
+            ))),
+        };
+        if data.len() < 4 {
+            // The journal is only renamed into place once complete, so this is not a reseal
+            self.remove_journal();
+            return Ok(false);
+        }
+        let nv_index = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let blob = &data[4..];
+
+        self.invalidate_cache();
+        let tcti = self.tcti_context()?;
+        let restored = match unsafe { self.load_key(nv_index) } {
+            // Either the old key was never deleted, or the new one was stored
+            Ok(_) => false,
+            Err(TotpError::SecretNotFound) => {
+                let rc = unsafe {
+                    retry(|| tpm2totp_storeKey_nv(
+                        blob.as_ptr(),
+                        blob.len() as size_t,
+                        nv_index,
+                        tcti
+                    ))
+                };
+                if rc != 0 {
+                    return Err(TotpError::from_rc(rc));
+                }
+                true
+            },
+            Err(err) => return Err(err),
+        };
+        self.remove_journal();
+        Ok(restored)
+    }
+
+    unsafe fn discover_key(&mut self) -> Result<(AutoFree<u8>, size_t), TotpError> {
+        for nv_index in self.nv_indices()? {
+            if nv_index == self.nv_index {
+                continue;
+            }
+            if let Ok(key) = self.load_key(nv_index) {
+                self.discovered_index = Some(nv_index);
+                return Ok(key);
+            }
+        }
+        Err(TotpError::SecretNotFound)
+    }
+
     pub fn show(&mut self) -> Result<TotpCode, TotpError> {
+        let start = Instant::now();
+        let mut result = self.show_ffi();
+        // Retry once over a new connection, so that a reset TPM driver does not break every call
+        if matches!(&result, Err(err) if err.is_transport()) && self.reconnect().is_ok() {
+            result = self.show_ffi();
+        }
+        self.timings.show = Some(start.elapsed());
+        self.show_fallback(result)
+    }
+
+    #[cfg(not(feature = "cli-fallback"))]
+    fn show_fallback(&mut self, result: Result<TotpCode, TotpError>) -> Result<TotpCode, TotpError> {
+        result
+    }
+
+    // Calculate the code with the tpm2-totp command, for systems where the library misbehaves
+    #[cfg(feature = "cli-fallback")]
+    fn show_fallback(&mut self, result: Result<TotpCode, TotpError>) -> Result<TotpCode, TotpError> {
+        match result {
+            Err(err @ TotpError::Unknown(_)) | Err(err @ TotpError::Other(_)) => {
+                self.fallback_error = Some(err);
+            },
+            result => return result,
+        }
+
+        let output = std::process::Command::new("tpm2-totp")
+            .arg("--nvindex")
+            .arg(format!("0x{:08X}", self.nv_index))
+            .arg("show")
+            .output()
+            .map_err(|err| TotpError::Other(format!(
+                "failed to run tpm2-totp command: {}", err
+            )))?;
+
+        if ! output.status.success() {
+            return Err(TotpError::Other(format!(
+                "tpm2-totp command failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        // The code is the last word of the output, optionally preceded by the time
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout.split_whitespace()
+            .last()
+            .and_then(|word| word.parse::<u64>().ok())
+            .map(TotpCode)
+            .ok_or_else(|| TotpError::Other(format!(
+                "failed to parse tpm2-totp command output: {}", stdout.trim()
+            )))
+    }
+
+    // Load the key blob, reusing the one loaded earlier in the current window
+    fn cached_key(&mut self) -> Result<&[u8], TotpError> {
+        let window = unix_time() / TOTP_PERIOD;
+        if ! matches!(&self.key_cache, Some(cache) if cache.window == window) {
+            self.key_cache = None;
+            let blob = unsafe {
+                let (key_blob, key_blob_size) = match self.load_key(self.nv_index) {
+                    Err(TotpError::SecretNotFound) if self.autodiscover => self.discover_key()?,
+                    result => result?,
+                };
+                slice::from_raw_parts(key_blob.0, key_blob_size as usize).to_vec()
+            };
+            self.key_cache = Some(KeyCache { window, blob });
+        }
+
+        match &self.key_cache {
+            Some(cache) => Ok(&cache.blob),
+            None => unreachable!(),
+        }
+    }
+
+    /// Whether a secret is stored at the NV index.
+    pub fn enrolled(&mut self) -> Result<bool, TotpError> {
+        match self.cached_key() {
+            Ok(_) => Ok(true),
+            Err(TotpError::SecretNotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Whether the secret was stored with a recovery password, which `recover` and `commit_reseal`
+    /// need.
+    pub fn has_recovery_password(&mut self) -> Result<bool, TotpError> {
+        use tss_esapi::constants::tss::*;
+        // tpm2-totp only allows user authorization of the key when a password was set
+        let info = KeyInfo::parse(self.cached_key()?)?;
+        Ok(info.public.publicArea.objectAttributes & TPMA_OBJECT_USERWITHAUTH != 0)
+    }
+
+    /// The policy digest that sealing the secret now would give, computed from the current values
+    /// of the PCRs and banks the stored secret is bound to. It is compared with the digest the
+    /// secret was sealed under by `pcr_state_matches`.
+    pub fn current_pcr_digest(&mut self) -> Result<Vec<u8>, TotpError> {
+        use tss_esapi::constants::tss::*;
+        let info = KeyInfo::parse(self.cached_key()?)?;
+        let selection = bank_pcr_selection(info.pcrs, info.banks);
+
+        // At most eight PCRs are read per call, so the selection is read until none remain
+        let mut pcr_hasher = Sha256::new();
+        let esys = self.esys()?;
+        let mut remaining = selection;
         unsafe {
-            let mut key_blob = AutoFree(ptr::null_mut());
-            let mut key_blob_size = 0;
-            let mut rc = tpm2totp_loadKey_nv(
-                Self::NVRAM_INDEX,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
-                &mut key_blob.0,
-                &mut key_blob_size
-            );
-            if rc != 0 {
-                return Err(TotpError::from_rc(rc));
-            }
+            loop {
+                let mut update_counter = 0;
+                let mut selection_out: AutoFree<TPML_PCR_SELECTION> = AutoFree(ptr::null_mut());
+                let mut values: AutoFree<TPML_DIGEST> = AutoFree(ptr::null_mut());
+                let rc = retry(|| Esys_PCR_Read(
+                    esys.0,
+                    ESYS_TR_NONE,
+                    ESYS_TR_NONE,
+                    ESYS_TR_NONE,
+                    &remaining,
+                    &mut update_counter,
+                    &mut selection_out.0,
+                    &mut values.0
+                ));
+                if rc != 0 {
+                    return Err(TotpError::from_rc(rc as libc::c_int));
+                }
 
-            let mut now = 0;
-            let mut totp = 0;
-            rc = tpm2totp_calculate(
-                key_blob.0,
-                key_blob_size,
-                self.context.tcti_context_ptr() as *mut TSS2_TCTI_CONTEXT,
-                &mut now,
-                &mut totp
-            );
-            if rc != 0 {
-                return Err(TotpError::from_rc(rc));
+                let values = &*values.0;
+                if values.count == 0 {
+                    break;
+                }
+                for digest in &values.digests[..values.count as usize] {
+                    pcr_hasher.update(&digest.buffer[..digest.size as usize]);
+                }
+
+                let selection_out = &*selection_out.0;
+                for read in &selection_out.pcrSelections[..selection_out.count as usize] {
+                    let bank = remaining.pcrSelections[..remaining.count as usize].iter_mut()
+                        .find(|bank| bank.hash == read.hash);
+                    if let Some(bank) = bank {
+                        for (select, read) in bank.pcrSelect.iter_mut().zip(read.pcrSelect.iter()) {
+                            *select &= ! read;
+                        }
+                    }
+                }
             }
+        }
 
-            Ok(TotpCode(totp))
+        // tpm2-totp seals with a single PolicyPCR, extending an all zero digest
+        let mut buffer = vec![0; mem::size_of::<TPML_PCR_SELECTION>()];
+        let mut offset = 0;
+        let rc = unsafe {
+            Tss2_MU_TPML_PCR_SELECTION_Marshal(&selection, buffer.as_mut_ptr(), buffer.len() as _, &mut offset)
+        };
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc as libc::c_int));
         }
+        buffer.truncate(offset as usize);
+
+        let mut policy_hasher = Sha256::new();
+        policy_hasher.update(&[0u8; 32]);
+        policy_hasher.update(&TPM2_CC_PolicyPCR.to_be_bytes());
+        policy_hasher.update(&buffer);
+        policy_hasher.update(&pcr_hasher.finalize());
+        Ok(policy_hasher.finalize().to_vec())
+    }
+
+    /// Whether the PCRs the secret is bound to still have the values it was sealed under. Once they
+    /// change, `show` fails with `SystemStateChanged` and the secret needs resealing.
+    pub fn pcr_state_matches(&mut self) -> Result<bool, TotpError> {
+        let sealed = {
+            let info = KeyInfo::parse(self.cached_key()?)?;
+            let policy = &info.public.publicArea.authPolicy;
+            policy.buffer[..policy.size as usize].to_vec()
+        };
+        Ok(self.current_pcr_digest()? == sealed)
+    }
+
+    fn show_ffi(&mut self) -> Result<TotpCode, TotpError> {
+        let (key_blob, key_blob_size) = {
+            let blob = self.cached_key()?;
+            (blob.as_ptr(), blob.len())
+        };
+
+        let result = unsafe { self.calculate(key_blob, key_blob_size) };
+        if let Err(TotpError::SystemStateChanged) = result {
+            self.invalidate_cache();
+        }
+        result
+    }
+
+    unsafe fn calculate(&self, key_blob: *const u8, key_blob_size: usize) -> Result<TotpCode, TotpError> {
+        let tcti = self.tcti_context()?;
+        let mut now = 0;
+        let mut totp = 0;
+        let rc = retry(|| tpm2totp_calculate(
+            key_blob,
+            key_blob_size as size_t,
+            tcti,
+            &mut now,
+            &mut totp
+        ));
+        if rc != 0 {
+            return Err(TotpError::from_rc(rc));
+        }
+
+        Ok(TotpCode(totp))
+    }
+
+    /// The codes of every NV index holding a secret, for systems with several secrets bound to
+    /// different PCRs. The TPM is enumerated once, and a secret whose code cannot be calculated
+    /// does not keep the others from being shown.
+    pub fn show_all(&mut self) -> Result<Vec<SlotCode>, TotpError> {
+        let mut codes = Vec::new();
+        for nv_index in self.nv_indices()? {
+            let (key_blob, key_blob_size) = match unsafe { self.load_key(nv_index) } {
+                Ok(ok) => ok,
+                // Not a tpm2-totp secret
+                Err(_) => continue,
+            };
+            let blob = unsafe { slice::from_raw_parts(key_blob.0, key_blob_size as usize) };
+            codes.push(SlotCode {
+                nv_index,
+                pcrs: KeyInfo::parse(blob)?.pcrs,
+                code: unsafe { self.calculate(key_blob.0, key_blob_size as usize) },
+            });
+        }
+        Ok(codes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tss_esapi::constants::tss::*;
+
+    #[test]
+    fn from_rc_nv_space_full() {
+        assert!(matches!(TotpError::from_rc(TPM2_RC_NV_SPACE as _), TotpError::NvSpaceFull));
+    }
+
+    #[test]
+    fn from_rc_not_started() {
+        assert!(matches!(TotpError::from_rc(TPM2_RC_INITIALIZE as _), TotpError::NotStarted));
+    }
+
+    #[test]
+    fn from_rc_out_of_memory() {
+        assert!(matches!(TotpError::from_rc(TPM2_RC_OBJECT_MEMORY as _), TotpError::TpmBusy));
+        assert!(matches!(TotpError::from_rc(TPM2_RC_SESSION_MEMORY as _), TotpError::TpmBusy));
+    }
+
+    #[test]
+    fn secret_valid_length() {
+        let secret = TotpSecret::new(vec![0; SECRET_LEN]).unwrap();
+        assert_eq!(secret.len(), SECRET_LEN);
+    }
+
+    #[test]
+    fn secret_invalid_length() {
+        assert!(matches!(TotpSecret::new(Vec::new()), Err(TotpError::InvalidSecret)));
+        assert!(matches!(TotpSecret::new(vec![0; SECRET_LEN - 1]), Err(TotpError::InvalidSecret)));
+        assert!(matches!(TotpSecret::new(vec![0; SECRET_LEN + 1]), Err(TotpError::InvalidSecret)));
+    }
+
+    #[test]
+    fn secret_base32_groups() {
+        let secret = TotpSecret::new(b"12345678901234567890".to_vec()).unwrap();
+        assert_eq!(secret.as_base32_groups(), "GEZD GNBV GY3T QOJQ GEZD GNBV GY3T QOJQ");
     }
 }